@@ -0,0 +1,53 @@
+// sending to every `tokio` channel in a `WeakSet`, for simple event buses.
+
+use core::future::Future;
+use alloc::vec::Vec;
+use tokio::sync::mpsc::Sender;
+use crate::weakset::WeakSet;
+
+/// send a message to every channel in a set
+pub trait Broadcast<M> {
+    /// send a clone of `msg` to every channel, waiting for room in each of them.
+    ///
+    /// the channels whose receiver was dropped are removed from the set,
+    /// remaining entries of them are left without a value like with `WeakSet::clear`.
+    fn broadcast(&self, msg: M) -> impl Future<Output=()>;
+}
+
+impl<M: Clone> Broadcast<M> for WeakSet<Sender<M>> {
+    async fn broadcast(&self, msg: M) {
+        // the set must not be borrowed across an await, as other tasks may use it meanwhile.
+        // so take a clone of all senders first.
+        let targets: Vec<(usize, u32, Sender<M>)> = self.iter()
+            .map(|entry| (entry.index(), entry.generation(), entry.borrow().clone()))
+            .collect();
+
+        let mut closed = Vec::new();
+        for (index, generation, sender) in targets {
+            if sender.send(msg.clone()).await.is_err() {
+                closed.push((index, generation));
+            }
+        }
+        self.remove_slots(&closed);
+    }
+}
+
+#[tokio::test]
+async fn test_broadcast() {
+    use tokio::sync::mpsc::channel;
+
+    let mut set = WeakSet::new();
+    let (tx_a, mut rx_a) = channel(4);
+    let (tx_b, rx_b) = channel(4);
+    let a = set.insert(tx_a);
+    let b = set.insert(tx_b);
+    drop(rx_b);
+
+    set.broadcast(42).await;
+    assert_eq!(rx_a.recv().await, Some(42));
+    assert_eq!(set.len(), 1);
+    assert_eq!(b.refcount(), 0);
+
+    drop(a);
+    assert!(set.is_empty());
+}
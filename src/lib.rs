@@ -1,7 +1,23 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![feature(manually_drop_take)]
 
+// `WeakSet` only needs `alloc`. the others need `std` for `HashMap` and `Mutex`.
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod rcset;
+#[cfg(feature = "std")]
 pub use rcset::RcSet;
 
 pub mod weakset;
 pub use weakset::WeakSet;
+
+#[cfg(feature = "std")]
+pub mod sync;
+#[cfg(feature = "std")]
+pub use sync::SyncWeakSet;
+
+#[cfg(feature = "tokio")]
+pub mod broadcast;
+#[cfg(feature = "tokio")]
+pub use broadcast::Broadcast;
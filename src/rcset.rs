@@ -1,89 +1,328 @@
 use std::rc::{Rc, Weak};
-use std::cell::{RefCell, Ref};
-use std::collections::hash_map::{HashMap, Values as HashMapValues};
-use std::mem::{ManuallyDrop, transmute};
-use std::iter::Iterator;
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+#[cfg(not(feature = "ordered"))]
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::mem::ManuallyDrop;
+use std::iter::{Iterator, FusedIterator};
+use std::marker::PhantomData;
+use std::vec;
+use std::fmt;
+use std::ops::Deref;
 
-unsafe fn fix_values_lifetime<'a, 'b, T>(values: HashMapValues<'a, *const T, Weak<T>>) -> HashMapValues<'b, *const T, Weak<T>> {
-    transmute(values)
+// the keys are pointers, so `S` can be a much simpler hasher than the default SipHash.
+// they are unique even for zero-sized `T`: every `Rc` has its own allocation (with the counts),
+// and the weak in the map keeps it from being reused while the key exists.
+//
+// with the `ordered` feature, the map keeps the insertion order, so `iter` is deterministic.
+#[cfg(not(feature = "ordered"))]
+type Map<T, S> = HashMap<*const T, Weak<T>, S>;
+#[cfg(feature = "ordered")]
+type Map<T, S> = indexmap::IndexMap<*const T, Weak<T>, S>;
+
+// remove a key, keeping the order of the others
+fn remove_key<T, S: BuildHasher>(map: &mut Map<T, S>, ptr: *const T) {
+    #[cfg(not(feature = "ordered"))]
+    map.remove(&ptr);
+    #[cfg(feature = "ordered")]
+    map.shift_remove(&ptr);
 }
 
+/// a set of `Rc`s that only keeps a weak reference to each value.
+///
+/// with the `ordered` feature, removing a key shifts all keys after it, which is O(n).
+/// so dropping the last `Item` of a value costs O(n), and dropping all n items O(n²).
 #[derive(Debug)]
-pub struct RcSet<T> {
-    inner: Rc<RefCell<HashMap<*const T, Weak<T>>>>
+pub struct RcSet<T, S = RandomState> {
+    inner: Rc<RefCell<Map<T, S>>>
 }
 impl<T> RcSet<T> {
     pub fn new() -> RcSet<T> {
-        RcSet { inner: Rc::new(RefCell::new(HashMap::new())) }
-    }
-    pub fn insert(&mut self, item: T) -> Item<T> {
-        let rc = Rc::new(item);
-        let (rc, rc_ptr) = unsafe {
-            let ptr = Rc::into_raw(rc);
-            let rc = Rc::from_raw(ptr);
-            (rc, ptr)
-        };
-        let weak = Rc::downgrade(&rc);
-        self.inner.borrow_mut().insert(rc_ptr, weak);
-        
+        RcSet { inner: Rc::new(RefCell::new(Map::new())) }
+    }
+
+    /// create a set with room for `cap` items before it needs to grow
+    pub fn with_capacity(cap: usize) -> RcSet<T> {
+        RcSet { inner: Rc::new(RefCell::new(Map::with_capacity(cap))) }
+    }
+}
+impl<T, S: BuildHasher> RcSet<T, S> {
+    /// create a set that uses the given hasher for the pointer keys
+    pub fn with_hasher(hasher: S) -> RcSet<T, S> {
+        RcSet { inner: Rc::new(RefCell::new(Map::with_hasher(hasher))) }
+    }
+
+    /// reserve room for at least `additional` more items
+    pub fn reserve(&mut self, additional: usize) {
+        self.inner.borrow_mut().reserve(additional);
+    }
+
+    /// number of items the set can hold without growing
+    pub fn capacity(&self) -> usize {
+        self.inner.borrow().capacity()
+    }
+
+    pub fn insert(&mut self, item: T) -> Item<T, S> {
+        self.insert_rc(Rc::new(item))
+    }
+
+    /// add a value that is already in an `Rc`.
+    /// if it is part of the set already, this returns another item for it.
+    pub fn insert_rc(&mut self, rc: Rc<T>) -> Item<T, S> {
+        // an existing key of the pointer has to be for this value,
+        // as the weak in the map keeps the allocation alive.
+        self.inner.borrow_mut().entry(Rc::as_ptr(&rc)).or_insert_with(|| Rc::downgrade(&rc));
+
         Item {
             rc: ManuallyDrop::new(rc),
             set: self.clone()
         }
     }
-    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
-        unsafe {
-            let inner = self.inner.borrow();
-            let values = fix_values_lifetime(inner.values());
-            Iter {
-                _ref: inner,
-                // transmute values to escape the borrow. this could be safe since we keep the Ref alive
-                iter: values
+    pub fn iter<'a>(&'a self) -> Iter<'a, T, S> {
+        // upgrade everything while the map is borrowed, so the iterator does not need to keep the borrow.
+        // this also means items can be inserted and dropped while iterating.
+        Iter {
+            iter: self.to_vec().into_iter(),
+            _set: PhantomData
+        }
+    }
+
+    /// the `Rc`s of all live items.
+    /// the `Vec` does not borrow the set, but keeps the values alive (and part of the set) until it is dropped.
+    pub fn to_vec(&self) -> Vec<Rc<T>> {
+        self.inner.borrow().values().filter_map(Weak::upgrade).collect()
+    }
+
+    /// number of live items.
+    /// a key whose value is already gone (but not removed yet) is not counted.
+    pub fn len(&self) -> usize {
+        self.inner.borrow().values().filter(|weak| weak.strong_count() > 0).count()
+    }
+
+    /// number of live items, the same as `len`
+    pub fn live_len(&self) -> usize {
+        self.len()
+    }
+
+    /// number of keys in the map, including those of values that are already gone.
+    /// this is more than `live_len` while there are keys left for `prune` to remove.
+    pub fn map_len(&self) -> usize {
+        self.inner.borrow().len()
+    }
+
+    /// true if the set holds no live items
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// true if a live item is equal to `val`.
+    /// this compares against every item, so it takes O(n).
+    pub fn contains(&self, val: &T) -> bool where T: PartialEq {
+        self.find(val).is_some()
+    }
+
+    /// true if `rc` holds a value of this set.
+    /// this looks up the pointer, so unlike `contains` it takes O(1) and ignores equal values of other `Rc`s.
+    pub fn contains_rc(&self, rc: &Rc<T>) -> bool {
+        self.inner.borrow().get(&Rc::as_ptr(rc)).is_some_and(|weak| weak.strong_count() > 0)
+    }
+
+    // the first live item equal to `val`
+    fn find(&self, val: &T) -> Option<Rc<T>> where T: PartialEq {
+        self.inner.borrow().values()
+        .filter_map(Weak::upgrade)
+        .find(|rc| **rc == *val)
+    }
+
+    /// return an item for a value equal to `val` if there is one, otherwise insert `val`.
+    /// this can be used to intern values.
+    ///
+    /// the set is indexed by pointer, so looking for an equal value takes O(n).
+    pub fn get_or_insert(&mut self, val: T) -> Item<T, S> where T: Eq {
+        self.entry(val).or_insert()
+    }
+
+    /// look for a value equal to `val`, and return an item for it or a way to insert `val`.
+    /// like `get_or_insert`, this takes O(n).
+    pub fn entry(&mut self, val: T) -> RcEntry<T, S> where T: Eq {
+        match self.find(&val) {
+            Some(rc) => RcEntry::Occupied(Item {
+                rc: ManuallyDrop::new(rc),
+                set: self.clone()
+            }),
+            None => RcEntry::Vacant(VacantEntry {
+                set: self.clone(),
+                val
+            })
+        }
+    }
+
+    /// remove all items from the set.
+    /// items that are still alive keep their value, but are no longer part of the set.
+    pub fn clear(&mut self) {
+        self.inner.borrow_mut().clear();
+    }
+
+    /// remove the items for which `f` returns false, along with keys of values that are already gone.
+    ///
+    /// like with `clear`, removed items keep their value. dropping them later does not touch the set:
+    /// their value is still allocated, so no new item can have the same pointer in the meantime.
+    pub fn retain<F: FnMut(&Rc<T>) -> bool>(&mut self, mut f: F) {
+        // `f` is called on a snapshot, so it may use the set
+        let remove: Vec<*const T> = self.to_vec().iter()
+            .filter(|rc| !f(rc))
+            .map(Rc::as_ptr)
+            .collect();
+
+        {
+            let mut inner = self.inner.borrow_mut();
+            for ptr in remove {
+                remove_key(&mut inner, ptr);
             }
         }
+        self.prune();
     }
 
+    /// remove the keys of values that are already gone.
+    ///
+    /// this happens when an `Rc` (from `as_rc` or `iter`) outlives the last `Item` of a value,
+    /// as only dropping an `Item` removes the key.
+    pub fn prune(&mut self) {
+        self.inner.borrow_mut().retain(|_, weak| weak.strong_count() > 0);
+    }
+
+    // a value stays in the set as long as there is any strong reference to it,
+    // even if that is an `Rc` obtained from `as_rc` or `iter` that outlives the last `Item`.
+    // in that case the key is only removed by a later `prune`, `retain` or `clear`.
     pub fn drop_item(&self, item: Rc<T>) {
-        let refcount = Rc::strong_count(&item);
-        if refcount <= 1 {
-            // get the pointer of the rc
-            let rc_ptr = unsafe {
-                let ptr = Rc::into_raw(item);
-                drop(Rc::from_raw(ptr));
-                ptr
-            };
-            self.inner.borrow_mut().remove(&rc_ptr);
+        // get the pointer of the rc
+        let rc_ptr = Rc::as_ptr(&item);
+
+        // drop it before borrowing the map, as the value's destructor may use the set.
+        // the weak in the map keeps the allocation alive, so the pointer cannot be reused in the meantime.
+        drop(item);
+
+        let mut inner = self.inner.borrow_mut();
+        let is_dead = inner.get(&rc_ptr).is_some_and(|weak| weak.strong_count() == 0);
+        if is_dead {
+            remove_key(&mut inner, rc_ptr);
         }
     }
 }
-impl<T> Clone for RcSet<T> {
+impl<T, S: BuildHasher + Default> Default for RcSet<T, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+impl<T, S> Clone for RcSet<T, S> {
     fn clone(&self) -> Self {
         RcSet { inner: self.inner.clone() }
     }
 }
 
-pub struct Iter<'a, T> {
-    _ref: Ref<'a, HashMap<*const T, Weak<T>>>,
-    iter: HashMapValues<'a, *const T, Weak<T>>
+pub struct Iter<'a, T, S = RandomState> {
+    iter: vec::IntoIter<Rc<T>>,
+    _set: PhantomData<&'a RcSet<T, S>>
 }
-impl<'a, T> Iterator for Iter<'a, T> {
+impl<'a, T, S> Iterator for Iter<'a, T, S> {
     type Item = Rc<T>;
     fn next(&mut self) -> Option<Rc<T>> {
-        while let Some(weak) = self.iter.next() {
-            if let Some(rc) = Weak::upgrade(weak) {
-                return Some(rc);
-            }
+        self.iter.next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, T, S> FusedIterator for Iter<'a, T, S> {}
+// the iterator owns its `Rc`s, so a clone just clones them
+impl<'a, T, S> Clone for Iter<'a, T, S> {
+    fn clone(&self) -> Self {
+        Iter {
+            iter: self.iter.clone(),
+            _set: PhantomData
         }
-        None
+    }
+}
+
+impl<'a, T, S: BuildHasher> IntoIterator for &'a RcSet<T, S> {
+    type Item = Rc<T>;
+    type IntoIter = Iter<'a, T, S>;
+    fn into_iter(self) -> Iter<'a, T, S> {
+        self.iter()
     }
 }
 
 #[derive(Clone)]
-pub struct Item<T> {
+pub struct Item<T, S: BuildHasher = RandomState> {
     rc: ManuallyDrop<Rc<T>>,
-    set: RcSet<T>
+    set: RcSet<T, S>
+}
+impl<T, S: BuildHasher> Item<T, S> {
+    /// get a clone of the `Rc` holding the value
+    pub fn as_rc(&self) -> Rc<T> {
+        Rc::clone(&self.rc)
+    }
+
+    /// get a weak reference to the value.
+    /// upgrading it gives a plain `Rc`, not an `Item`, so it doesn't keep the value in the set by itself.
+    pub fn downgrade(&self) -> Weak<T> {
+        Rc::downgrade(&self.rc)
+    }
+}
+/// the result of `RcSet::entry`
+pub enum RcEntry<T, S: BuildHasher = RandomState> {
+    /// an item for the equal value in the set
+    Occupied(Item<T, S>),
+
+    /// there is no equal value in the set
+    Vacant(VacantEntry<T, S>)
+}
+impl<T, S: BuildHasher> RcEntry<T, S> {
+    /// the item of the equal value, or of the inserted one
+    pub fn or_insert(self) -> Item<T, S> {
+        match self {
+            RcEntry::Occupied(item) => item,
+            RcEntry::Vacant(vacant) => vacant.insert()
+        }
+    }
+}
+
+/// a value that is not in the set yet, see `RcSet::entry`
+pub struct VacantEntry<T, S: BuildHasher = RandomState> {
+    set: RcSet<T, S>,
+    val: T
 }
-impl<T> Drop for Item<T> {
+impl<T, S: BuildHasher> VacantEntry<T, S> {
+    /// the value that would be inserted
+    pub fn value(&self) -> &T {
+        &self.val
+    }
+
+    /// insert the value into the set
+    pub fn insert(mut self) -> Item<T, S> {
+        self.set.insert(self.val)
+    }
+
+    /// get the value back without inserting it
+    pub fn into_value(self) -> T {
+        self.val
+    }
+}
+
+impl<T, S: BuildHasher> Deref for Item<T, S> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.rc
+    }
+}
+impl<T: fmt::Debug, S: BuildHasher> fmt::Debug for Item<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Item").field(&**self.rc).finish()
+    }
+}
+impl<T, S: BuildHasher> Drop for Item<T, S> {
     fn drop(&mut self) {
         unsafe {
             self.set.drop_item(ManuallyDrop::take(&mut self.rc))
@@ -108,4 +347,382 @@ fn test_rcset() {
     let _3 = _2.clone();
     println!("set: {:?}", set);
     set.iter().for_each(|v| println!("{:?}", v));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_rcset_hasher() {
+    use std::hash::{BuildHasherDefault, Hasher};
+
+    // the keys are pointers, so the address itself is a good enough hash
+    #[derive(Default)]
+    struct PtrHasher(u64);
+    impl Hasher for PtrHasher {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+        fn write(&mut self, bytes: &[u8]) {
+            for &b in bytes {
+                self.0 = (self.0 << 8) | b as u64;
+            }
+        }
+        fn write_usize(&mut self, n: usize) {
+            self.0 = n as u64;
+        }
+    }
+
+    let mut set: RcSet<i32, BuildHasherDefault<PtrHasher>> = RcSet::default();
+    let a = set.insert(1);
+    let _b = set.insert(2);
+    drop(a);
+
+    let values: Vec<i32> = set.iter().map(|rc| *rc).collect();
+    assert_eq!(values, [2]);
+}
+
+#[test]
+fn test_rcset_iter_hold() {
+    let mut set = RcSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+
+    let mut iter = set.iter();
+    let first = iter.next().unwrap();
+
+    // the map is not borrowed by the iterator
+    let c = set.clone().insert(3);
+    drop(a);
+    drop(b);
+
+    let mut held: Vec<i32> = iter.map(|rc| *rc).collect();
+    held.push(*first);
+    held.sort();
+    assert_eq!(held, [1, 2]);
+
+    drop(first);
+    assert_eq!(set.iter().map(|rc| *rc).collect::<Vec<_>>(), [3]);
+    drop(c);
+}
+
+#[test]
+fn test_rcset_len() {
+    let mut set = RcSet::new();
+    assert!(set.is_empty());
+
+    let a = set.insert(1);
+    let b = set.insert(2);
+    assert_eq!(set.len(), 2);
+
+    // a dead weak that is still in the map is not counted
+    let rc = Rc::new(3);
+    set.inner.borrow_mut().insert(Rc::as_ptr(&rc), Rc::downgrade(&rc));
+    drop(rc);
+    assert_eq!(set.inner.borrow().len(), 3);
+    assert_eq!(set.len(), 2);
+
+    drop(a);
+    drop(b);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_rcset_clear() {
+    let mut set = RcSet::new();
+    let a = set.insert(String::from("a"));
+
+    set.clear();
+    assert!(set.is_empty());
+    assert_eq!(set.iter().count(), 0);
+
+    // the item still owns its value, and dropping it does not touch the set
+    assert_eq!(**a.rc, "a");
+    drop(a);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_rcset_contains() {
+    let mut set = RcSet::new();
+    let _a = set.insert(1);
+    let b = set.insert(2);
+    let _c = set.insert(3);
+    drop(b);
+
+    assert!(set.contains(&1));
+    assert!(!set.contains(&2));
+    assert!(set.contains(&3));
+    assert!(!set.contains(&4));
+}
+
+#[test]
+fn test_rcset_get_or_insert() {
+    let mut set = RcSet::new();
+    let a = set.get_or_insert(String::from("hello"));
+    let b = set.get_or_insert(String::from("hello"));
+    let c = set.get_or_insert(String::from("world"));
+
+    assert!(Rc::ptr_eq(&a.rc, &b.rc));
+    assert!(!Rc::ptr_eq(&a.rc, &c.rc));
+    assert_eq!(set.len(), 2);
+
+    // the value stays interned until the last item is dropped
+    drop(a);
+    assert!(set.contains(&String::from("hello")));
+    drop(b);
+    assert!(!set.contains(&String::from("hello")));
+}
+
+#[test]
+fn test_rcset_item_debug() {
+    let mut set = RcSet::new();
+    let a = set.insert("hello");
+    assert_eq!(format!("{:?}", a), "Item(\"hello\")");
+}
+
+#[test]
+fn test_rcset_as_rc() {
+    let mut set = RcSet::new();
+    let a = set.insert(String::from("hello"));
+    assert_eq!(a.len(), 5);
+
+    let rc = a.as_rc();
+    drop(rc);
+    assert_eq!(set.len(), 1);
+    assert_eq!(*a, "hello");
+}
+
+#[test]
+fn test_rcset_outlive_item() {
+    let mut set = RcSet::new();
+    let a = set.insert(1);
+    let rc = a.as_rc();
+
+    // the value is still alive, so it is still part of the set
+    drop(a);
+    assert_eq!(set.iter().map(|rc| *rc).collect::<Vec<_>>(), [1]);
+    assert_eq!(set.len(), 1);
+
+    drop(rc);
+    assert_eq!(set.iter().count(), 0);
+    assert!(set.is_empty());
+
+    // the last item removes the key
+    let b = set.insert(2);
+    drop(b);
+    assert_eq!(set.inner.borrow().len(), 1);
+}
+
+#[test]
+fn test_rcset_to_vec() {
+    let mut set = RcSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+    drop(b);
+
+    let rcs = set.to_vec();
+    assert_eq!(rcs.len(), 1);
+    assert!(Rc::ptr_eq(&rcs[0], &a.rc));
+
+    // the snapshot keeps the value alive
+    drop(a);
+    assert_eq!(set.len(), 1);
+    drop(rcs);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_rcset_contains_rc() {
+    let mut set = RcSet::new();
+    let a = set.insert(1);
+    let rc = a.as_rc();
+    assert!(set.contains_rc(&rc));
+    assert!(!set.contains_rc(&Rc::new(1)));
+
+    set.clear();
+    assert!(!set.contains_rc(&rc));
+}
+
+#[test]
+fn test_rcset_capacity() {
+    let mut set = RcSet::with_capacity(100);
+    let cap = set.capacity();
+    assert!(cap >= 100);
+
+    let items: Vec<_> = (0 .. 100).map(|i| set.insert(i)).collect();
+    assert_eq!(set.capacity(), cap);
+    assert_eq!(set.len(), 100);
+
+    set.reserve(100);
+    assert!(set.capacity() >= 200);
+    drop(items);
+}
+
+#[test]
+fn test_rcset_retain() {
+    let mut set = RcSet::new();
+    let items: Vec<_> = (0 .. 5).map(|i| set.insert(i)).collect();
+
+    set.retain(|rc| **rc % 2 == 0);
+    let mut values: Vec<i32> = set.iter().map(|rc| *rc).collect();
+    values.sort();
+    assert_eq!(values, [0, 2, 4]);
+
+    // the removed items still have their value, and dropping them leaves the set alone
+    assert_eq!(*items[1], 1);
+    drop(items);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_rcset_prune() {
+    let mut set = RcSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+
+    // nothing to do while everything is alive
+    set.prune();
+    assert_eq!(set.inner.borrow().len(), 2);
+
+    // the last strong reference is not an item, so the key is left behind
+    let rc = b.as_rc();
+    drop(b);
+    drop(rc);
+    assert_eq!(set.inner.borrow().len(), 2);
+
+    set.prune();
+    assert_eq!(set.inner.borrow().len(), 1);
+    assert_eq!(set.iter().map(|rc| *rc).collect::<Vec<_>>(), [1]);
+    drop(a);
+}
+
+#[test]
+fn test_rcset_into_iter() {
+    let mut set = RcSet::new();
+    let _items: Vec<_> = (1 ..= 4).map(|i| set.insert(i)).collect();
+
+    let mut sum = 0;
+    for rc in &set {
+        sum += *rc;
+    }
+    assert_eq!(sum, 10);
+}
+
+#[test]
+fn test_rcset_downgrade() {
+    let mut set = RcSet::new();
+    let a = set.insert(1);
+    let weak = a.downgrade();
+    assert_eq!(weak.upgrade().as_deref(), Some(&1));
+
+    drop(a);
+    assert!(weak.upgrade().is_none());
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_rcset_insert_rc() {
+    let mut set = RcSet::new();
+    let rc = Rc::new(String::from("shared"));
+
+    let a = set.insert_rc(rc.clone());
+    assert!(Rc::ptr_eq(&a.rc, &rc));
+    assert!(set.contains_rc(&rc));
+
+    // inserting it again does not add another key
+    let b = set.insert_rc(rc.clone());
+    assert_eq!(set.inner.borrow().len(), 1);
+
+    // the value stays as long as any item (or the `Rc`) does
+    drop(a);
+    assert!(set.contains_rc(&rc));
+    drop(b);
+    assert_eq!(set.len(), 1);
+    drop(rc);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_rcset_zero_sized() {
+    let mut set = RcSet::new();
+    let a = set.insert(());
+    let b = set.insert(());
+    assert!(!Rc::ptr_eq(&a.rc, &b.rc));
+    assert_eq!(set.len(), 2);
+    assert_eq!(set.iter().count(), 2);
+
+    drop(a);
+    assert_eq!(set.len(), 1);
+    let _c = set.insert(());
+    assert_eq!(set.len(), 2);
+    drop(b);
+}
+
+#[test]
+fn test_rcset_iter_clone() {
+    let mut set = RcSet::new();
+    let _items: Vec<_> = (0 .. 3).map(|i| set.insert(i)).collect();
+
+    let mut iter = set.iter();
+    iter.next();
+    let rest: Vec<i32> = iter.clone().map(|rc| *rc).collect();
+    assert_eq!(rest.len(), 2);
+    assert_eq!(iter.map(|rc| *rc).collect::<Vec<_>>(), rest);
+}
+
+#[test]
+fn test_rcset_live_len() {
+    let mut set = RcSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+    assert_eq!(set.live_len(), 2);
+    assert_eq!(set.map_len(), 2);
+
+    // an `Rc` that outlives the last item leaves the key behind
+    let rc = b.as_rc();
+    drop(b);
+    drop(rc);
+    assert_eq!(set.live_len(), 1);
+    assert_eq!(set.map_len(), 2);
+
+    set.prune();
+    assert_eq!(set.map_len(), 1);
+    drop(a);
+    assert_eq!(set.map_len(), 0);
+}
+
+#[test]
+fn test_rcset_entry() {
+    let mut set = RcSet::new();
+    let a = set.insert(1);
+
+    match set.entry(1) {
+        RcEntry::Occupied(item) => assert!(Rc::ptr_eq(&item.rc, &a.rc)),
+        RcEntry::Vacant(_) => panic!("1 is in the set")
+    }
+
+    let b = match set.entry(2) {
+        RcEntry::Occupied(_) => panic!("2 is not in the set"),
+        RcEntry::Vacant(vacant) => {
+            assert_eq!(*vacant.value(), 2);
+            vacant.insert()
+        }
+    };
+    assert_eq!(set.len(), 2);
+    assert!(set.contains_rc(&b.rc));
+
+    if let RcEntry::Vacant(vacant) = set.entry(3) {
+        assert_eq!(vacant.into_value(), 3);
+    }
+    assert_eq!(set.len(), 2);
+}
+
+#[cfg(feature = "ordered")]
+#[test]
+fn test_rcset_ordered() {
+    let mut set = RcSet::new();
+    let mut items: Vec<_> = (0 .. 100).map(|i| set.insert(i)).collect();
+    assert_eq!(set.iter().map(|rc| *rc).collect::<Vec<_>>(), (0 .. 100).collect::<Vec<_>>());
+
+    // removing an item keeps the order of the others
+    items.remove(10);
+    assert_eq!(set.iter().map(|rc| *rc).filter(|&i| i < 12).collect::<Vec<_>>(), [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 11]);
+}
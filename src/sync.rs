@@ -0,0 +1,283 @@
+// a thread-safe `WeakSet`.
+// it uses the same slot storage, behind an `Arc<Mutex<_>>` instead of an `Rc<RefCell<_>>`.
+
+use std::{
+    sync::{Arc, Mutex, MutexGuard, PoisonError, atomic::{AtomicUsize, Ordering}},
+    ops::{Deref, DerefMut},
+    fmt
+};
+use crate::weakset::WeakSetInner;
+
+pub struct SyncWeakSet<T> {
+    inner: Arc<SyncWeakSetShared<T>>
+}
+
+// like for `WeakSet`, the number of live iterators is kept outside of the lock,
+// so dropping an iterator does not wait for (or deadlock with) a borrow.
+struct SyncWeakSetShared<T> {
+    slots: Mutex<WeakSetInner<T>>,
+    iterators: AtomicUsize
+}
+
+impl<T> Clone for SyncWeakSet<T> {
+    fn clone(&self) -> Self {
+        SyncWeakSet {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+impl<T> Default for SyncWeakSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SyncWeakSetEntry<T> {
+    set: SyncWeakSet<T>,
+    index: usize,
+    generation: u32
+}
+
+impl<T> SyncWeakSet<T> {
+    pub fn new() -> SyncWeakSet<T> {
+        SyncWeakSet {
+            inner: Arc::new(SyncWeakSetShared {
+                slots: Mutex::new(WeakSetInner::with_slots(Vec::new())),
+                iterators: AtomicUsize::new(0)
+            })
+        }
+    }
+
+    // a panic while the lock was held (e.g. in `borrow_mut`) cannot leave the slots inconsistent,
+    // so poisoning is ignored.
+    fn lock(&self) -> MutexGuard<'_, WeakSetInner<T>> {
+        self.inner.slots.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // note: this needs &mut self for consistency with `WeakSet::insert`.
+    // other handles may still insert concurrently, see iter() for details.
+    pub fn insert(&mut self, val: T) -> SyncWeakSetEntry<T> {
+        let (index, generation) = {
+            // iterators register while holding the lock, so the count is up to date here
+            let mut inner = self.lock();
+            inner.insert(val, self.inner.iterators.load(Ordering::Relaxed) > 0)
+        };
+        SyncWeakSetEntry {
+            set: self.clone(),
+            index,
+            generation
+        }
+    }
+
+    fn make_entry(&self, index: usize, generation: u32) -> Option<SyncWeakSetEntry<T>> {
+        if self.lock().acquire(index, generation) {
+            Some(SyncWeakSetEntry {
+                set: self.clone(),
+                index,
+                generation
+            })
+        } else {
+            None
+        }
+    }
+
+    fn drop_entry(&self, index: usize, generation: u32) {
+        // the value is dropped after releasing the lock, as its destructor may use the set.
+        let val = self.lock().release(index, generation);
+        drop(val);
+    }
+
+    pub fn iter(&self) -> SyncWeakSetIter<'_, T> {
+        // Unlike `WeakSet`, other threads can insert while we iterate.
+        // Free slots are not reused while an iterator is alive, so new values are appended to the used list
+        // and may or may not be visited, but no live entry is skipped.
+        let inner = self.lock();
+        self.inner.iterators.fetch_add(1, Ordering::Relaxed);
+        SyncWeakSetIter {
+            set: self,
+            next: inner.first_used()
+        }
+    }
+
+    /// call `f` on all live entries in parallel, using the rayon thread pool.
+    ///
+    /// the entries are collected first, so the set is not locked while `f` runs,
+    /// and every value stays alive until all calls are done.
+    /// borrowing an entry locks the whole set though, so only the work outside of `borrow` runs in parallel.
+    #[cfg(feature = "rayon")]
+    pub fn par_for_each<F>(&self, f: F) where T: Send, F: Fn(&SyncWeakSetEntry<T>) + Sync + Send {
+        use rayon::prelude::*;
+
+        let entries: Vec<SyncWeakSetEntry<T>> = self.iter().collect();
+        entries.par_iter().for_each(f);
+    }
+
+    /// number of live entries in the set
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// true if the set holds no live entries
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct SyncWeakSetIter<'a, T> {
+    set: &'a SyncWeakSet<T>,
+
+    // the next slot to visit
+    next: Option<usize>
+}
+impl<'a, T> Iterator for SyncWeakSetIter<'a, T> {
+    type Item = SyncWeakSetEntry<T>;
+    fn next(&mut self) -> Option<SyncWeakSetEntry<T>> {
+        while let Some(index) = self.next {
+            let (generation, next) = self.set.lock().visit(index);
+            self.next = next;
+            if let Some(generation) = generation {
+                // the slot may have been freed by another thread in between
+                if let Some(entry) = self.set.make_entry(index, generation) {
+                    return Some(entry);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<'a, T> Drop for SyncWeakSetIter<'a, T> {
+    fn drop(&mut self) {
+        self.set.inner.iterators.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<'a, T> IntoIterator for &'a SyncWeakSet<T> {
+    type Item = SyncWeakSetEntry<T>;
+    type IntoIter = SyncWeakSetIter<'a, T>;
+    fn into_iter(self) -> SyncWeakSetIter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> Drop for SyncWeakSetEntry<T> {
+    fn drop(&mut self) {
+        self.set.drop_entry(self.index, self.generation);
+    }
+}
+
+impl<T> Clone for SyncWeakSetEntry<T> {
+    fn clone(&self) -> Self {
+        self.set.make_entry(self.index, self.generation).unwrap()
+    }
+}
+
+impl<T> SyncWeakSetEntry<T> {
+    /// the index of the slot this entry refers to
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// borrow the value stored in the set.
+    /// this locks the whole set until the guard is dropped,
+    /// so using the same set in the meantime (even dropping one of its entries) deadlocks.
+    ///
+    /// the lock makes this exclusive like `borrow_mut`, but the value can only be read through the guard.
+    pub fn borrow(&self) -> SyncWeakSetRef<'_, T> {
+        SyncWeakSetRef(self.borrow_mut())
+    }
+
+    /// mutably borrow the value stored in the set.
+    /// this locks the whole set until the guard is dropped.
+    pub fn borrow_mut(&self) -> SyncWeakSetGuard<'_, T> {
+        SyncWeakSetGuard {
+            inner: self.set.lock(),
+            index: self.index,
+            generation: self.generation
+        }
+    }
+}
+
+/// a locked value of a `SyncWeakSet`, that can only be read
+pub struct SyncWeakSetRef<'a, T>(SyncWeakSetGuard<'a, T>);
+impl<'a, T> Deref for SyncWeakSetRef<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// a locked value of a `SyncWeakSet`
+pub struct SyncWeakSetGuard<'a, T> {
+    inner: MutexGuard<'a, WeakSetInner<T>>,
+    index: usize,
+    generation: u32
+}
+impl<'a, T> Deref for SyncWeakSetGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.inner.slot(self.index, self.generation).unwrap()
+    }
+}
+impl<'a, T> DerefMut for SyncWeakSetGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner.slot_mut(self.index, self.generation).unwrap()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SyncWeakSet<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.lock();
+        f.debug_set().entries(inner.slots().iter()).finish()
+    }
+}
+
+#[test]
+fn test_sync_set() {
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+    assert_send::<SyncWeakSet<i32>>();
+    assert_sync::<SyncWeakSet<i32>>();
+    assert_send::<SyncWeakSetEntry<i32>>();
+
+    let mut set = SyncWeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+    assert_eq!(set.len(), 2);
+
+    let handle = {
+        let mut set = set.clone();
+        std::thread::spawn(move || {
+            *b.borrow_mut() += 10;
+            set.insert(3)
+        })
+    };
+    let c = handle.join().unwrap();
+
+    let values: Vec<i32> = set.iter().map(|e| *e.borrow()).collect();
+    assert_eq!(values, [1, 3]);
+    assert_eq!(*c.borrow(), 3);
+
+    drop(a);
+    drop(c);
+    assert!(set.is_empty());
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_par_for_each() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    let mut set = SyncWeakSet::new();
+    let entries: Vec<_> = (0 .. 10_000u64).map(|i| set.insert(i)).collect();
+
+    let sum = AtomicU64::new(0);
+    set.par_for_each(|entry| {
+        sum.fetch_add(*entry.borrow(), Ordering::Relaxed);
+    });
+    let serial: u64 = set.iter().map(|entry| *entry.borrow()).sum();
+    assert_eq!(sum.into_inner(), serial);
+    assert_eq!(serial, 9_999 * 10_000 / 2);
+    drop(entries);
+}
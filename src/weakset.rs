@@ -23,19 +23,50 @@ decisions:
 
 problems:
     - insertion will be fairly terrible when looking for new slots at position 0.
-      to solve this, store the position of the first free slot.
+      to solve this, the empty slots form a linked list of free slots, starting at `first_free`.
 */
 
-use std::{
+use core::{
+    cell::{Cell, RefCell, Ref, RefMut, BorrowError, BorrowMutError},
+    fmt,
+    mem,
+    ops::Deref,
+    hash::{Hash, Hasher},
+    iter::{FusedIterator, Rev},
+    any::Any
+};
+use alloc::{
     rc::Rc,
-    cell::{RefCell, Ref, RefMut},
-    fmt
+    vec::Vec,
+    boxed::Box
 };
 
 pub struct WeakSet<T> {
-    inner: Rc<RefCell<WeakSetInner<T>>>
+    inner: Rc<WeakSetShared<T>>
+}
+
+// the slots, and next to them the number of live iterators.
+// the count is outside of the `RefCell`, so iterators can be created and dropped while a value is borrowed.
+struct WeakSetShared<T> {
+    slots: RefCell<WeakSetInner<T, DropHook>>,
+    iterators: Cell<usize>
+}
+impl<T> WeakSetShared<T> {
+    fn new(inner: WeakSetInner<T, DropHook>) -> Rc<WeakSetShared<T>> {
+        Rc::new(WeakSetShared { slots: RefCell::new(inner), iterators: Cell::new(0) })
+    }
+}
+// the slots are used far more often, so `inner.borrow()` goes straight to them
+impl<T> Deref for WeakSetShared<T> {
+    type Target = RefCell<WeakSetInner<T, DropHook>>;
+    fn deref(&self) -> &Self::Target {
+        &self.slots
+    }
 }
 
+// called with the index of a slot that was freed by dropping its last entry
+type DropHook = Option<Rc<dyn Fn(usize)>>;
+
 // #[derive(Clone)] fails, so do it manually
 impl<T> Clone for WeakSet<T> {
     fn clone(&self) -> Self {
@@ -45,191 +76,2811 @@ impl<T> Clone for WeakSet<T> {
     }
 }
 
+impl<T> Default for WeakSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct WeakSetEntry<T> {
     set: WeakSet<T>,
-    index: usize
+    index: usize,
+    generation: u32
 }
 
-struct WeakSetInner<T> {
+// the slot storage, shared with `SyncWeakSet`
+pub(crate) struct WeakSetInner<T, H = ()> {
     slots: Vec<WeakSetSlot<T>>,
-    first_free: usize
+
+    // head of the free list
+    first_free: Option<usize>,
+
+    // head and tail of the used list
+    first_used: Option<usize>,
+    last_used: Option<usize>,
+
+    // number of used slots
+    len: usize,
+
+    // the most live entries the set accepts, `None` if unbounded
+    max_len: Option<usize>,
+
+    // the generation of the next inserted value.
+    // every value gets a new one, so an entry can tell if its slot has been reused since.
+    generation: u32,
+
+    // state that only one of the set types needs (the drop hook of `WeakSet`)
+    hook: H,
+
+    #[cfg(feature = "metrics")]
+    metrics: WeakSetMetrics
 }
-impl<T> WeakSetInner<T> {
-    fn slot(&self, index: usize) -> Option<&T> {
+impl<T, H> WeakSetInner<T, H> {
+    pub(crate) fn with_slots(slots: Vec<WeakSetSlot<T>>) -> WeakSetInner<T, H> where H: Default {
+        WeakSetInner {
+            slots,
+            first_free: None,
+            first_used: None,
+            last_used: None,
+            len: 0,
+            max_len: None,
+            generation: 0,
+            hook: H::default(),
+            #[cfg(feature = "metrics")]
+            metrics: WeakSetMetrics::default()
+        }
+    }
+    // true if no more values can be inserted
+    fn is_full(&self) -> bool {
+        self.max_len.is_some_and(|max| self.len >= max)
+    }
+    // accessors for `SyncWeakSet`
+    #[cfg(feature = "std")]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+    #[cfg(feature = "std")]
+    pub(crate) fn slots(&self) -> &[WeakSetSlot<T>] {
+        &self.slots
+    }
+    #[cfg(feature = "std")]
+    pub(crate) fn first_used(&self) -> Option<usize> {
+        self.first_used
+    }
+    // the generation of the value in the slot, `None` if it is empty
+    pub(crate) fn generation(&self, index: usize) -> Option<u32> {
+        match self.slots.get(index) {
+            Some(&WeakSetSlot::Used { generation, .. }) => Some(generation),
+            _ => None
+        }
+    }
+    // the value in the slot, if it is still the one of the given generation
+    pub(crate) fn slot(&self, index: usize, generation: u32) -> Option<&T> {
+        match self.slots.get(index) {
+            Some(WeakSetSlot::Used { value, generation: g, .. }) if *g == generation => Some(value),
+            _ => None
+        }
+    }
+    pub(crate) fn slot_mut(&mut self, index: usize, generation: u32) -> Option<&mut T> {
+        self.slots.get_mut(index)?.value_mut(generation)
+    }
+    // number of references to the value, 0 if it is gone
+    pub(crate) fn refcount(&self, index: usize, generation: u32) -> usize {
+        match self.slots.get(index) {
+            Some(&WeakSetSlot::Used { refcount, generation: g, .. }) if g == generation => refcount,
+            _ => 0
+        }
+    }
+    fn links(&self, index: usize) -> Links {
         match self.slots[index] {
-            WeakSetSlot::Empty => None,
-            WeakSetSlot::Used(ref val, _) => Some(val)
+            WeakSetSlot::Empty { links, .. } => links,
+            WeakSetSlot::Used { links, .. } => links
         }
     }
-    fn slot_mut(&mut self, index: usize) -> Option<&mut T> {
+    fn links_mut(&mut self, index: usize) -> &mut Links {
         match self.slots[index] {
-            WeakSetSlot::Empty => None,
-            WeakSetSlot::Used(ref mut val, _) => Some(val)
+            WeakSetSlot::Empty { ref mut links, .. } => links,
+            WeakSetSlot::Used { ref mut links, .. } => links
+        }
+    }
+
+    // store the value with one reference and return its index and generation.
+    // `iterating` tells if the set has live iterators, which the caller keeps count of.
+    pub(crate) fn insert(&mut self, value: T, iterating: bool) -> (usize, u32) {
+        // take the first free slot, otherwise add one
+        let index = self.alloc(iterating);
+
+        // and store the value there with one reference
+        let generation = self.fill(index, value);
+        (index, generation)
+    }
+
+    // the live values, in the order of the used list
+    pub(crate) fn values(&self) -> impl Iterator<Item=&T> + '_ {
+        core::iter::successors(self.first_used, move |&index| self.links(index).next)
+        .map(move |index| match self.slots[index] {
+            WeakSetSlot::Used { ref value, .. } => value,
+            WeakSetSlot::Empty { .. } => unreachable!("empty slot in the used list")
+        })
+    }
+
+    // the slot the next `alloc` returns
+    fn next_slot(&self, iterating: bool) -> usize {
+        match self.first_free {
+            Some(index) if !iterating => index,
+            _ => self.slots.len()
+        }
+    }
+
+    // take a slot off the free list, or add one if there is none.
+    // the slot is still `Empty` afterwards, so the caller has to fill it.
+    fn alloc(&mut self, iterating: bool) -> usize {
+        match self.first_free {
+            // an iterator might currently point at a free slot, so only reuse them without iterators
+            Some(index) if !iterating => {
+                #[cfg(feature = "metrics")]
+                { self.metrics.slots_scanned += 1; }
+                match self.slots[index] {
+                    WeakSetSlot::Empty { next_free, .. } => self.first_free = next_free,
+                    WeakSetSlot::Used { .. } => unreachable!("used slot in the free list")
+                }
+                index
+            }
+            _ => {
+                #[cfg(feature = "metrics")]
+                if self.slots.len() == self.slots.capacity() {
+                    self.metrics.growths += 1;
+                }
+                self.slots.push(WeakSetSlot::Empty { next_free: None, links: Links::default() });
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    // store the value in an allocated slot with one reference, and append it to the used list.
+    // returns the generation of the value.
+    fn fill(&mut self, index: usize, value: T) -> u32 {
+        let generation = self.generation;
+        self.generation = self.generation.wrapping_add(1);
+
+        let links = Links { prev: self.last_used, next: None };
+        self.slots[index] = WeakSetSlot::Used { value, refcount: 1, generation, links };
+        match self.last_used {
+            Some(last) => self.links_mut(last).next = Some(index),
+            None => self.first_used = Some(index)
+        }
+        self.last_used = Some(index);
+        self.len += 1;
+        generation
+    }
+
+    // add a reference to the value of the given generation.
+    // returns false if the slot is empty, out of bounds or holds a different value by now.
+    pub(crate) fn acquire(&mut self, index: usize, generation: u32) -> bool {
+        match self.slots.get_mut(index) {
+            Some(WeakSetSlot::Used { refcount, generation: g, .. }) if *g == generation => {
+                // we are creating a new referernce, so bump the refcount.
+                // wrapping around would free the value while it is still referenced.
+                *refcount = refcount.checked_add(1).expect("WeakSetEntry refcount overflow");
+                true
+            }
+            _ => false
+        }
+    }
+
+    // remove a reference from a slot, freeing it when it was the last one.
+    // returns the value if the slot was freed.
+    pub(crate) fn release(&mut self, index: usize, generation: u32) -> Option<T> {
+        let is_empty = match self.slots.get_mut(index) {
+            Some(WeakSetSlot::Used { refcount, generation: g, .. }) if *g == generation => {
+                // decrement the refcount and see if it is zero
+                *refcount -= 1;
+                *refcount == 0
+            },
+            // the slot was already emptied (e.g. by `clear`) while this entry was alive,
+            // and possibly trimmed off by `shrink_to_fit` or reused since.
+            // there is nothing left to drop, and it is already on the free list.
+            _ => return None
+        };
+
+        // if it is empty now, free the slot
+        if is_empty {
+            self.free(index)
+        } else {
+            None
+        }
+    }
+
+    // look at the slot an iterator is about to visit.
+    // returns the generation of the value if it is used, and the index of the slot after it.
+    #[cfg(feature = "std")]
+    pub(crate) fn visit(&self, index: usize) -> (Option<u32>, Option<usize>) {
+        let (generation, links) = self.visit_links(index);
+        (generation, links.next)
+    }
+
+    // like `visit`, but return both neighbours, so the list can be followed in either direction
+    fn visit_links(&self, index: usize) -> (Option<u32>, Links) {
+        match self.slots.get(index) {
+            Some(&WeakSetSlot::Used { generation, links, .. }) => (Some(generation), links),
+            // freed since the iterator got here, but it still knows where the list continued
+            Some(&WeakSetSlot::Empty { links, .. }) => (None, links),
+            // trimmed away. this can't happen while the iterator is registered, but don't panic over it.
+            None => (None, Links::default())
+        }
+    }
+
+    // empty the slot, unlink it from the used list and put it on the free list.
+    // returns the value it held (if any).
+    pub(crate) fn free(&mut self, index: usize) -> Option<T> {
+        let links = match self.slots[index] {
+            WeakSetSlot::Empty { .. } => return None,
+            WeakSetSlot::Used { links, .. } => links
+        };
+        match links.prev {
+            Some(prev) => self.links_mut(prev).next = links.next,
+            None => self.first_used = links.next
+        }
+        match links.next {
+            Some(next) => self.links_mut(next).prev = links.prev,
+            None => self.last_used = links.prev
+        }
+        self.len -= 1;
+
+        // the empty slot keeps its links, so an iterator that is about to visit it can still find the next used slot
+        let empty = WeakSetSlot::Empty { next_free: self.first_free, links };
+        self.first_free = Some(index);
+        match mem::replace(&mut self.slots[index], empty) {
+            WeakSetSlot::Used { value, .. } => Some(value),
+            WeakSetSlot::Empty { .. } => unreachable!()
+        }
+    }
+
+    // remove empty slots from the end. returns false if there are live iterators,
+    // which could still visit those slots.
+    pub(crate) fn trim(&mut self, iterating: bool) -> bool {
+        if iterating {
+            return false;
+        }
+        while let Some(WeakSetSlot::Empty { .. }) = self.slots.last() {
+            self.slots.pop();
+        }
+        // some of the removed slots were on the free list
+        self.rebuild_free_list();
+        true
+    }
+
+    // link all empty slots into the free list in ascending order
+    pub(crate) fn rebuild_free_list(&mut self) {
+        let mut next = None;
+        for (index, slot) in self.slots.iter_mut().enumerate().rev() {
+            if let WeakSetSlot::Empty { ref mut next_free, .. } = slot {
+                *next_free = next;
+                next = Some(index);
+            }
         }
+        self.first_free = next;
+    }
+
+    // move all used slots to the front, keeping their order.
+    // returns the new index for every old slot, `None` for the empty ones.
+    pub(crate) fn compact(&mut self) -> Vec<Option<usize>> {
+        let mut next = 0;
+        let map: Vec<Option<usize>> = self.slots.iter().map(|slot| match slot {
+            WeakSetSlot::Used { .. } => {
+                next += 1;
+                Some(next - 1)
+            }
+            WeakSetSlot::Empty { .. } => None
+        }).collect();
+
+        // the used list only links used slots, so they all have a new index
+        let remap = |index: Option<usize>| index.map(|i| map[i].unwrap());
+        self.slots = mem::take(&mut self.slots).into_iter().filter_map(|slot| match slot {
+            WeakSetSlot::Used { value, refcount, generation, links } => Some(WeakSetSlot::Used {
+                value,
+                refcount,
+                generation,
+                links: Links { prev: remap(links.prev), next: remap(links.next) }
+            }),
+            WeakSetSlot::Empty { .. } => None
+        }).collect();
+        self.first_used = remap(self.first_used);
+        self.last_used = remap(self.last_used);
+        self.first_free = None;
+        map
     }
 }
 
-// this isn't `Option<(T, usize)>` because we might want to add information to `Empty`.
-// - previous and next used entry index for fast iteration
+// this isn't `Option<(T, usize)>` because `Empty` carries information as well:
 // - the next free entry index for fast insertion
-enum WeakSetSlot<T> {
-    // deleted 
-    Empty,
+// - previous and next used entry index (from when it was freed) so iteration can continue past it
+pub(crate) enum WeakSetSlot<T> {
+    // deleted
+    Empty { next_free: Option<usize>, links: Links },
+
+    // used with the number of references and the generation of the value
+    Used { value: T, refcount: usize, generation: u32, links: Links }
+}
+impl<T> WeakSetSlot<T> {
+    // the value, if it is the one of the given generation
+    fn value_mut(&mut self, generation: u32) -> Option<&mut T> {
+        match self {
+            WeakSetSlot::Used { value, generation: g, .. } if *g == generation => Some(value),
+            _ => None
+        }
+    }
+}
 
-    // used with the number of references
-    Used(T, usize)
+// position in the list of used slots
+#[derive(Copy, Clone, Default)]
+pub(crate) struct Links {
+    prev: Option<usize>,
+    next: Option<usize>
 }
 
 impl<T> WeakSet<T> {
     pub fn new() -> WeakSet<T> {
         WeakSet {
-            inner: Rc::new(RefCell::new(WeakSetInner { slots: Vec::new(), first_free: 0 })) 
+            inner: WeakSetShared::new(WeakSetInner::with_slots(Vec::new()))
         }
     }
 
-    // note: this needs &mut self to ensure proper iterator behaviour.
-    // see iter() for details.
-    pub fn insert(&mut self, val: T) -> WeakSetEntry<T> {
-        // get a mutable reference
+    /// create a set with room for `cap` entries before the slots need to grow
+    pub fn with_capacity(cap: usize) -> WeakSet<T> {
+        WeakSet {
+            inner: WeakSetShared::new(WeakSetInner::with_slots(Vec::with_capacity(cap)))
+        }
+    }
+
+    /// create a set with `n` empty slots, which are filled by the first `n` inserts in ascending order.
+    /// unlike `with_capacity` the slots already exist, so these inserts only take them off the free list.
+    pub fn with_free_slots(n: usize) -> WeakSet<T> {
+        let slots = (0 .. n).map(|_| WeakSetSlot::Empty { next_free: None, links: Links::default() }).collect();
+        let mut inner = WeakSetInner::with_slots(slots);
+        inner.rebuild_free_list();
+        WeakSet {
+            inner: WeakSetShared::new(inner)
+        }
+    }
+
+    /// create a set that holds at most `limit` live entries.
+    /// inserting into a full set fails with `try_insert` and panics with `insert`.
+    pub fn with_max_capacity(limit: usize) -> WeakSet<T> {
+        let set = WeakSet::new();
+        set.inner.borrow_mut().max_len = Some(limit);
+        set
+    }
+
+    /// build a set from the values, along with the entries that keep them alive.
+    ///
+    /// there is deliberately no `FromIterator` impl: it would have to drop the entries,
+    /// and with them every value it just inserted.
+    pub fn from_iter_entries<I: IntoIterator<Item=T>>(values: I) -> (WeakSet<T>, Vec<WeakSetEntry<T>>) {
+        let values = values.into_iter();
+        let mut set = WeakSet::with_capacity(values.size_hint().0);
+        let entries = set.extend_entries(values);
+        (set, entries)
+    }
+
+    /// create a set from the values, along with their entries in the same order.
+    /// the slots are allocated once, and the values take the slots `0..vals.len()`.
+    pub fn from_vec(vals: Vec<T>) -> (WeakSet<T>, Vec<WeakSetEntry<T>>) {
+        // unlike a size hint, the length is exact, so neither the slots nor the entries have to grow
+        let mut set = WeakSet::with_capacity(vals.len());
+        let mut entries = Vec::with_capacity(vals.len());
+        for val in vals {
+            entries.push(set.insert(val));
+        }
+        (set, entries)
+    }
+
+    /// create an independent set with clones of the live values, along with the entries that keep them alive.
+    /// (`clone` only creates another handle of the same set)
+    pub fn deep_clone(&self) -> (WeakSet<T>, Vec<WeakSetEntry<T>>) where T: Clone {
+        WeakSet::from_iter_entries(self.to_vec())
+    }
+
+    /// another handle of the same set, the same as `clone`.
+    /// values inserted through one handle show up in all of them.
+    #[must_use]
+    pub fn share(&self) -> WeakSet<T> {
+        self.clone()
+    }
+
+    /// true if both handles refer to the same set.
+    /// like `Rc::ptr_eq`, it can be called as `WeakSet::ptr_eq(&a, &b)`.
+    pub fn ptr_eq(&self, other: &WeakSet<T>) -> bool {
+        Rc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// reserve room for at least `additional` more slots
+    pub fn reserve(&mut self, additional: usize) {
+        self.inner.borrow_mut().slots.reserve(additional);
+    }
+
+    /// remove the empty slots at the end and shrink the capacity as much as possible.
+    ///
+    /// the slots are left alone while the set is being iterated (through another handle).
+    pub fn shrink_to_fit(&mut self) {
         let mut inner = self.inner.borrow_mut();
-        
-        // try to find a 'Free' slot first, otherwise add one
-        let slot_idx = inner.slots.iter()
-        .skip(inner.first_free)
-        .position(|slot|
-            match slot {
-                WeakSetSlot::Empty => true,
-                _ => false
-            }
-        )
-        .map(|off| inner.first_free + off)
-        .unwrap_or_else(|| {
-            inner.slots.push(WeakSetSlot::Empty);
-            inner.slots.len() - 1
-        });
-        inner.first_free = slot_idx + 1;
+        if inner.trim(self.iterating()) {
+            inner.slots.shrink_to_fit();
+        }
+    }
+
+    /// like `shrink_to_fit`, but keep a capacity of at least `min_capacity`.
+    ///
+    /// the capacity never drops below the last used slot, even if it is above `min_capacity`.
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        let mut inner = self.inner.borrow_mut();
+        // after trimming, the last slot is a used one, and `Vec::shrink_to` keeps all slots.
+        if inner.trim(self.iterating()) {
+            inner.slots.shrink_to(min_capacity);
+        }
+    }
+
+    /// move all values to the front of the slots and drop the empty ones after them.
+    ///
+    /// this changes the index of the values, so `entries` has to contain every entry of the set, which are updated.
+    /// weak entries can't be updated, and those of moved values can no longer be upgraded.
+    /// returns the new index for every old slot, `None` for the ones that were empty.
+    ///
+    /// panics if there are entries that are not in `entries`, or if the set is being iterated.
+    pub fn compact(&mut self, entries: &mut [WeakSetEntry<T>]) -> Vec<Option<usize>> {
+        let mut inner = self.inner.borrow_mut();
+        assert!(!self.iterating(), "cannot compact a WeakSet while it is iterated");
+
+        // count the given handles per slot, they have to account for every reference.
+        // entries whose value has been removed (e.g. by `clear`) don't refer to anything and are left alone.
+        let mut handles = alloc::vec![0; inner.slots.len()];
+        for entry in entries.iter() {
+            assert!(Rc::ptr_eq(&self.inner, &entry.set.inner), "entry belongs to a different set");
+            if inner.generation(entry.index) == Some(entry.generation) {
+                handles[entry.index] += 1;
+            }
+        }
+        for (index, &count) in handles.iter().enumerate() {
+            let refcount = inner.generation(index).map_or(0, |generation| inner.refcount(index, generation));
+            assert_eq!(count, refcount, "compact needs all entries of the set");
+        }
+
+        let map = inner.compact();
+        for entry in entries {
+            if let Some(&Some(index)) = map.get(entry.index) {
+                // the old slot may have been reused by a different value
+                if inner.generation(index) == Some(entry.generation) {
+                    entry.index = index;
+                }
+            }
+        }
+        map
+    }
+
+    /// number of used slots, the same as `len`
+    pub fn live_count(&self) -> usize {
+        self.len()
+    }
+
+    /// number of slots, including the empty ones
+    pub fn slot_count(&self) -> usize {
+        self.inner.borrow().slots.len()
+    }
+
+    /// the slot the next `insert` will reuse, `None` if there are no free slots (and a slot will be added).
+    /// slots are not reused while the set is iterated though.
+    pub fn first_free_index(&self) -> Option<usize> {
+        self.inner.borrow().first_free
+    }
+
+    /// the share of empty slots, between 0 and 1.
+    /// this can help to decide when to call `compact` or `shrink_to_fit`.
+    pub fn fragmentation(&self) -> f32 {
+        let inner = self.inner.borrow();
+        if inner.slots.is_empty() {
+            return 0.0;
+        }
+        (inner.slots.len() - inner.len) as f32 / inner.slots.len() as f32
+    }
+
+    /// how much work `insert` has done so far
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> WeakSetMetrics {
+        self.inner.borrow().metrics
+    }
+
+    /// number of slots the set can hold without reallocating
+    pub fn capacity(&self) -> usize {
+        self.inner.borrow().slots.capacity()
+    }
+
+    // note: this takes &mut self, so the set can't be iterated (through this handle) at the same time.
+    // see `insert_shared` otherwise.
+    pub fn insert(&mut self, val: T) -> WeakSetEntry<T> {
+        self.insert_shared(val)
+    }
+
+    /// insert `val` through a shared reference, e.g. while iterating.
+    ///
+    /// the value is not visited by iterators that already exist (see `iter`).
+    /// panics if the set is at its maximum capacity.
+    pub fn insert_shared(&self, val: T) -> WeakSetEntry<T> {
+        match self.push(val) {
+            Ok(entry) => entry,
+            Err((err, _)) => panic!("{}", err)
+        }
+    }
 
-        // construct an entry with one reference
-        let new_slot = WeakSetSlot::Used(val, 1);
+    /// insert `val`, or give it back if the set already holds as many entries as `with_max_capacity` allows
+    pub fn try_insert(&mut self, val: T) -> Result<WeakSetEntry<T>, (WeakSetError, T)> {
+        self.push(val)
+    }
 
-        // and assign it to the index (we could check that the previous value was `Empty`…)
-        inner.slots[slot_idx] = new_slot;
+    // store the value unless the set is full
+    fn push(&self, val: T) -> Result<WeakSetEntry<T>, (WeakSetError, T)> {
+        let (slot_idx, generation) = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.is_full() {
+                return Err((WeakSetError::CapacityExceeded, val));
+            }
+            inner.insert(val, self.iterating())
+        };
 
         // finally construct a reference to it
+        Ok(WeakSetEntry {
+            set: self.clone(),
+            index: slot_idx,
+            generation
+        })
+    }
+
+    /// insert the value returned by `f`, which is given the index of the slot it will be stored in.
+    /// this allows values that contain their own index.
+    ///
+    /// `f` may read the set, but panics if it changes which slot is next (by inserting or freeing a slot).
+    /// panics if the set is at its maximum capacity.
+    pub fn insert_with<F: FnOnce(usize) -> T>(&mut self, f: F) -> WeakSetEntry<T> {
+        // the set is not borrowed while `f` runs, only the slot is looked up before
+        let index = {
+            let inner = self.inner.borrow();
+            if inner.is_full() {
+                drop(inner);
+                panic!("{}", WeakSetError::CapacityExceeded);
+            }
+            inner.next_slot(self.iterating())
+        };
+        let val = f(index);
+
+        let generation = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.is_full() || inner.next_slot(self.iterating()) != index {
+                // release the borrow first, as dropping `val` may use the set
+                drop(inner);
+                panic!("the set was changed while creating the value for insert_with");
+            }
+            inner.insert(val, self.iterating()).1
+        };
         WeakSetEntry {
             set: self.clone(),
-            index: slot_idx
+            index,
+            generation
+        }
+    }
+
+    /// insert a fixed number of values, returning their entries in an array
+    pub fn insert_all<const N: usize>(&mut self, vals: [T; N]) -> [WeakSetEntry<T>; N] {
+        self.reserve(N);
+        vals.map(|val| self.insert(val))
+    }
+
+    /// insert `val` unless an equal value is already in the set, in which case its entry is returned as the error.
+    /// this compares against every value, so it takes O(n).
+    pub fn insert_unique(&mut self, val: T) -> Result<WeakSetEntry<T>, WeakSetEntry<T>> where T: Eq {
+        match self.find(|v| *v == val) {
+            Some(entry) => Err(entry),
+            None => Ok(self.insert(val))
         }
     }
 
-    // common method to create an entry from thin air
+    /// insert all values, returning their entries.
+    ///
+    /// this is not an `Extend` impl for the same reason there is no `FromIterator`.
+    pub fn extend_entries<I: IntoIterator<Item=T>>(&mut self, values: I) -> Vec<WeakSetEntry<T>> {
+        let values = values.into_iter();
+        self.reserve(values.size_hint().0);
+        values.map(|val| self.insert(val)).collect()
+    }
+
+    // common method to create an entry from thin air, for whatever value is in the slot
     fn make_entry(&self, index: usize) -> Option<WeakSetEntry<T>> {
-        match self.inner.borrow_mut().slots[index] {
-            WeakSetSlot::Empty => None,
-            WeakSetSlot::Used(_, ref mut refcount) => {
-                // we are creating a new referernce, so bump the refcount
-                *refcount += 1;
-                Some(WeakSetEntry {
-                    set: self.clone(),
-                    index
-                })
+        let generation = self.inner.borrow().generation(index)?;
+        self.make_checked_entry(index, generation)
+    }
+
+    // create an entry if the slot still holds the value of the given generation
+    fn make_checked_entry(&self, index: usize, generation: u32) -> Option<WeakSetEntry<T>> {
+        if self.inner.borrow_mut().acquire(index, generation) {
+            Some(WeakSetEntry {
+                set: self.clone(),
+                index,
+                generation
+            })
+        } else {
+            None
+        }
+    }
+
+    /// get an entry for the slot at `index`.
+    /// returns `None` if the slot is empty or out of bounds.
+    ///
+    /// together with `WeakSetEntry::index` this allows to store a plain index and recover the entry later.
+    /// the slot may hold a different value by then, use `get_checked` to rule that out.
+    #[doc(alias = "slot_entry")]
+    pub fn get(&self, index: usize) -> Option<WeakSetEntry<T>> {
+        self.make_entry(index)
+    }
+
+    /// like `get`, but also returns `None` if the slot holds a different value than
+    /// the one `generation` (from `WeakSetEntry::generation`) belongs to.
+    pub fn get_checked(&self, index: usize, generation: u32) -> Option<WeakSetEntry<T>> {
+        self.make_checked_entry(index, generation)
+    }
+
+    /// run `f` on the value of `entry` and return its result.
+    /// the set is only borrowed for the duration of `f`, so the borrow cannot be held by accident.
+    ///
+    /// panics if `entry` belongs to a different set.
+    pub fn with_mut<R, F: FnOnce(&mut T) -> R>(&self, entry: &WeakSetEntry<T>, f: F) -> R {
+        assert!(Rc::ptr_eq(&self.inner, &entry.set.inner), "entry belongs to a different set");
+        f(&mut *entry.borrow_mut())
+    }
+
+    /// mutably borrow the values of two entries at once, e.g. to swap them.
+    /// returns `None` if both refer to the same slot.
+    ///
+    /// panics if an entry belongs to a different set or has lost its value, or if the set is already borrowed.
+    pub fn borrow_two_mut(&self, a: &WeakSetEntry<T>, b: &WeakSetEntry<T>) -> Option<(RefMut<'_, T>, RefMut<'_, T>)> {
+        assert!(Rc::ptr_eq(&self.inner, &a.set.inner), "entry belongs to a different set");
+        assert!(Rc::ptr_eq(&self.inner, &b.set.inner), "entry belongs to a different set");
+        if a.index == b.index {
+            return None;
+        }
+        Some(RefMut::map_split(self.inner.borrow_mut(), |inner| {
+            let [x, y] = inner.slots.get_disjoint_mut([a.index, b.index]).unwrap();
+            (x.value_mut(a.generation).unwrap(), y.value_mut(b.generation).unwrap())
+        }))
+    }
+
+    /// mutably borrow the values in the slots at `indices` at once.
+    /// returns `None` if an index occurs twice, or a slot is empty or out of bounds.
+    ///
+    /// panics if the set is already borrowed.
+    pub fn get_disjoint_mut<const N: usize>(&self, indices: [usize; N]) -> Option<[RefMut<'_, T>; N]> {
+        let inner = self.inner.borrow_mut();
+        // check everything first, so the borrow can't fail halfway through splitting it
+        for (i, &index) in indices.iter().enumerate() {
+            if indices[..i].contains(&index) || inner.generation(index).is_none() {
+                return None;
             }
         }
+
+        // split the slots in ascending order of the indices
+        let mut order: [usize; N] = core::array::from_fn(|i| i);
+        order.sort_unstable_by_key(|&i| indices[i]);
+
+        let mut values: [Option<RefMut<'_, T>>; N] = core::array::from_fn(|_| None);
+        let mut rest = RefMut::map(inner, |inner| &mut inner.slots[..]);
+        let mut offset = 0;
+        for i in order {
+            let (_, tail) = RefMut::map_split(rest, |slots| slots.split_at_mut(indices[i] - offset));
+            let (slot, tail) = RefMut::map_split(tail, |slots| slots.split_first_mut().unwrap());
+            values[i] = Some(RefMut::map(slot, |slot| match slot {
+                WeakSetSlot::Used { value, .. } => value,
+                WeakSetSlot::Empty { .. } => unreachable!()
+            }));
+            rest = tail;
+            offset = indices[i] + 1;
+        }
+        Some(values.map(Option::unwrap))
     }
 
-    // decrease the refcount of the given entry, possibly dropping it
-    fn drop_entry(&self, index: usize) {
+    /// store `val` in the slot of `entry` and return the previous value.
+    /// the entry and its clones stay valid and see the new value.
+    ///
+    /// panics if `entry` belongs to a different set.
+    pub fn replace(&self, entry: &WeakSetEntry<T>, val: T) -> T {
+        assert!(Rc::ptr_eq(&self.inner, &entry.set.inner), "entry belongs to a different set");
+        mem::replace(&mut *entry.borrow_mut(), val)
+    }
+
+    /// take the value out of the set, if `entry` is its only entry. returns `None` otherwise.
+    /// the empty slots at the end are dropped, and the freed slot is reused by the next `insert`.
+    ///
+    /// unlike `Vec::swap_remove`, the last value is not moved into the freed slot,
+    /// as its entries would be left with the wrong index. `compact` can do that when given all entries.
+    /// `entry` is left without a value afterwards, like with `clear`.
+    ///
+    /// panics if `entry` belongs to a different set.
+    pub fn swap_remove(&mut self, entry: &WeakSetEntry<T>) -> Option<T> {
+        assert!(Rc::ptr_eq(&self.inner, &entry.set.inner), "entry belongs to a different set");
         let mut inner = self.inner.borrow_mut();
-        // get a reference to the slot
-        let ref mut slot = inner.slots[index];
-        let is_empty = match slot {
-            &mut WeakSetSlot::Used(_, ref mut refcount) => {
-                // decrement the refcount and see if it is zero
-                *refcount -= 1;
-                *refcount == 0
-            },
-            _ => unreachable!()
-        };
+        if inner.refcount(entry.index, entry.generation) != 1 {
+            return None;
+        }
+        let value = inner.free(entry.index);
+        inner.trim(self.iterating());
+        value
+    }
 
-        // if it is empty now, set the slot to empty (dropping the value in the process)
-        if is_empty {
-            *slot = WeakSetSlot::Empty;
-            if index < inner.first_free {
-                inner.first_free = index;
-            }
+    /// call `f` with the slot index whenever dropping the last entry of a value frees its slot.
+    /// this replaces the previous hook.
+    ///
+    /// slots freed by `clear`, `retain` and the like don't call it.
+    pub fn on_drop<F: Fn(usize) + 'static>(&mut self, f: F) {
+        self.inner.borrow_mut().hook = Some(Rc::new(f));
+    }
+
+    // decrease the refcount of the given entry, possibly dropping it
+    fn drop_entry(&self, index: usize, generation: u32) {
+        // the value and the hook are only used after releasing the borrow, as they may use the set.
+        let (val, hook) = {
+            let mut inner = self.inner.borrow_mut();
+            let val = inner.release(index, generation);
+            let hook = if val.is_some() { inner.hook.clone() } else { None };
+            (val, hook)
+        };
+        drop(val);
+        if let Some(hook) = hook {
+            hook(index);
         }
     }
 
-    pub fn iter<'a>(&'a self) -> impl Iterator<Item=WeakSetEntry<T>> + 'a {
+    // freed slots are not reused while there are live iterators, and trimming waits for them
+    fn iterating(&self) -> bool {
+        self.inner.iterators.get() > 0
+    }
+    fn add_iterator(&self) {
+        self.inner.iterators.set(self.inner.iterators.get() + 1);
+    }
+
+    pub fn iter(&self) -> WeakSetIter<'_, T> {
         // This is actually not easy.
-        // Items can be dropped any time during iteration.
-        // The good news is that at least no new items can be inserted (hence insert takes &mut self),
-        // meaning we can use indices for iteration.
+        // Items can be dropped any time during iteration, and inserted through `insert_shared` or other handles.
+        // We follow the used list by index.
+        // A slot that is freed keeps its links, so the iterator can continue from it.
+        // It is not reused while the iterator is alive, so values inserted in the meantime get new slots
+        // at the end of `slots` and the end of the used list. The iterator stops there.
         // We cannot borrow the inner storage for the iterator lifetime.
-
-        // the highest possible slot
-        let max_idx = self.inner.borrow().slots.len();
-        (0 .. max_idx).filter_map(move |idx| self.make_entry(idx))
+        // The iterator count is kept outside of it, so this works while a value is borrowed.
+        let inner = self.inner.borrow();
+        self.add_iterator();
+        WeakSetIter {
+            set: self,
+            front: inner.first_used,
+            back: inner.last_used,
+            front_generation: None,
+            back_generation: None,
+            end: inner.slots.len(),
+            remaining: inner.len
+        }
     }
-}
 
-impl<T> Drop for WeakSetEntry<T> {
-    fn drop(&mut self) {
-        self.set.drop_entry(self.index);
+    /// like `iter`, but in reverse insertion order, starting with the most recently inserted value.
+    /// (this is not the order of the slot indices, as freed slots are reused.)
+    ///
+    /// values inserted during iteration come after the start, so they are not visited either.
+    pub fn iter_rev(&self) -> Rev<WeakSetIter<'_, T>> {
+        self.iter().rev()
     }
-}
 
-impl<T> Clone for WeakSetEntry<T> {
-    fn clone(&self) -> Self {
-        self.set.make_entry(self.index).unwrap()
+    /// borrow all live values, in insertion order.
+    ///
+    /// the set stays borrowed until the iterator and all yielded refs are dropped,
+    /// so inserting or dropping the last entry of a value in the meantime panics.
+    pub fn iter_values(&self) -> impl Iterator<Item=Ref<'_, T>> + '_ {
+        // shared borrows can coexist, so every value gets a clone of the same borrow
+        let inner = self.inner.borrow();
+        let mut next = inner.first_used;
+        core::iter::from_fn(move || {
+            let index = next?;
+            next = inner.links(index).next;
+            Some(Ref::map(Ref::clone(&inner), |inner| match inner.slots[index] {
+                WeakSetSlot::Used { ref value, .. } => value,
+                WeakSetSlot::Empty { .. } => unreachable!("empty slot in the used list")
+            }))
+        })
     }
-}
 
-impl<T> WeakSetEntry<T> {
-    /// borrow the value stored in the set
-    pub fn borrow(&self) -> Ref<T> {
-        Ref::map(self.set.inner.borrow(), |inner| inner.slot(self.index).unwrap())
+    /// entries for all live values, in insertion order.
+    /// they keep the values alive as long as the `Vec` exists, and don't borrow the set.
+    pub fn collect_entries(&self) -> Vec<WeakSetEntry<T>> {
+        self.iter().collect()
     }
 
-    /// mutably borrow the value stored in the set
-    pub fn borrow_mut(&self) -> RefMut<T> {
-        RefMut::map(self.set.inner.borrow_mut(), |inner| inner.slot_mut(self.index).unwrap())
+    /// iterate over the entries together with their slot index
+    pub fn iter_indices(&self) -> impl Iterator<Item=(usize, WeakSetEntry<T>)> + '_ {
+        self.iter().map(|entry| (entry.index, entry))
     }
-}
 
-impl<T: fmt::Debug> fmt::Debug for WeakSet<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // this debug impl does not create references. 
-        // we can do this because we control the iterator and can be sure nothing will try to borrow the RefCell during iteration
+    /// the first entry (in insertion order) whose value matches `f`
+    pub fn find<F: FnMut(&T) -> bool>(&self, f: F) -> Option<WeakSetEntry<T>> {
+        // the set is only borrowed for the predicate, and released before the entry is created
+        let index = self.position(f)?;
+        self.make_entry(index)
+    }
 
+    /// the slot index of the first value (in insertion order) that matches `f`.
+    /// unlike `find` this does not create an entry, so the value may be gone by the time the index is used.
+    pub fn position<F: FnMut(&T) -> bool>(&self, mut f: F) -> Option<usize> {
         let inner = self.inner.borrow();
-        f.debug_set().entries(inner.slots.iter()).finish()
+        let mut cursor = inner.first_used;
+        loop {
+            let index = cursor?;
+            match inner.slots[index] {
+                WeakSetSlot::Used { ref value, links, .. } => {
+                    if f(value) {
+                        return Some(index);
+                    }
+                    cursor = links.next;
+                }
+                WeakSetSlot::Empty { .. } => unreachable!("empty slot in the used list")
+            }
+        }
     }
-}
 
-impl<T: fmt::Debug> fmt::Debug for WeakSetSlot<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            WeakSetSlot::Empty => write!(f, "empty"),
-            WeakSetSlot::Used(ref val, refcount) => write!(f, "{:?}({})", val, refcount)
+    /// call `f` on every live value, in insertion order.
+    ///
+    /// each value is only borrowed for its own call, and entries dropped in between are skipped.
+    /// `f` itself must not mutate the set (e.g. drop an entry), as the value is borrowed.
+    pub fn for_each<F: FnMut(&T)>(&self, mut f: F) {
+        for entry in self.iter() {
+            f(&entry.borrow());
         }
     }
-}
 
-#[test]
-fn test_set() {
-    let mut set = WeakSet::new();
-    let _0 = set.insert("hello world!");
-    println!("set: {:?}", set);
-    let _1 = set.insert("hello luna!");
-    println!("set: {:?}", set);
+    /// call `f` on every live value with mutable access, in insertion order.
+    ///
+    /// like with `for_each`, each value is only borrowed for its own call.
+    /// `f` must not use the set (not even borrow another entry), that panics.
+    pub fn for_each_mut<F: FnMut(&mut T)>(&self, mut f: F) {
+        for entry in self.iter() {
+            f(&mut entry.borrow_mut());
+        }
+    }
 
-    drop(_0);
-    println!("set: {:?}", set);
+    /// clone the live values into a `Vec`, in insertion order
+    pub fn to_vec(&self) -> Vec<T> where T: Clone {
+        // a single shared borrow covers all values, so nothing overlaps with a mutable borrow
+        self.inner.borrow().values().cloned().collect()
+    }
 
-    let _2 = set.insert("hello enso!");
-    println!("set: {:?}", set);
+    /// drop all values in the set.
+    ///
+    /// entries that are still alive lose their value: borrowing them panics and dropping them does nothing.
+    /// this stays that way when their slot is reused, as the new value has a different generation.
+    /// so this can also be used to tear down a set while there are still entries around.
+    #[doc(alias = "force_clear")]
+    pub fn clear(&mut self) {
+        // the values are moved out before dropping them, so their destructors don't run while the set is borrowed
+        drop(self.clear_and_collect());
+    }
 
-    let _3 = _2.clone();
-    println!("set: {:?}", set);
+    /// like `clear`, but return the values in insertion order instead of dropping them
+    pub fn clear_and_collect(&mut self) -> Vec<T> {
+        let mut inner = self.inner.borrow_mut();
+        let mut values = Vec::with_capacity(inner.len);
+        while let Some(index) = inner.first_used {
+            values.extend(inner.free(index));
+        }
+        inner.rebuild_free_list();
+        values
+    }
+
+    /// make the set as good as new, but keep its slots allocated:
+    /// all slots are free and are reused in ascending order.
+    ///
+    /// panics if the set still holds values or is being iterated, use `clear` first to drop the values.
+    /// entries left without a value by `clear` don't get a new one, as the generations keep counting.
+    pub fn reset(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        assert!(inner.len == 0, "cannot reset a WeakSet that still holds values");
+        assert!(!self.iterating(), "cannot reset a WeakSet while it is iterated");
+        for slot in inner.slots.iter_mut() {
+            *slot = WeakSetSlot::Empty { next_free: None, links: Links::default() };
+        }
+        inner.rebuild_free_list();
+    }
+
+    /// remove all values from the set, yielding them by value.
+    ///
+    /// like with `clear`, entries that are still alive are left without a value.
+    /// the values that are not consumed are dropped with the iterator.
+    pub fn drain(&mut self) -> WeakSetDrain<'_, T> {
+        WeakSetDrain { set: self }
+    }
+
+    /// take the values out of the set, in insertion order.
+    ///
+    /// this fails if there are other handles of the set (including weak entries, and entries left without a value),
+    /// which could still use it. entries of the values are not counted: like with `drain`, they are left without a value.
+    pub fn into_values(mut self) -> Result<Vec<T>, WeakSet<T>> {
+        let entries: usize = {
+            let inner = self.inner.borrow();
+            inner.slots.iter().map(|slot| match *slot {
+                WeakSetSlot::Used { refcount, .. } => refcount,
+                WeakSetSlot::Empty { .. } => 0
+            }).sum()
+        };
+        // every entry holds a handle of the set
+        if Rc::strong_count(&self.inner) != 1 + entries {
+            return Err(self);
+        }
+        Ok(self.drain().collect())
+    }
+
+    /// freeze the set, so this handle can only iterate and look up values until the guard is dropped.
+    /// as it borrows the set mutably, `insert` and `insert_shared` can't be called in the meantime.
+    ///
+    /// other handles of the set are not affected. (their insertions are not visited by the iterators, see `iter`)
+    pub fn iter_guarded(&mut self) -> Frozen<'_, T> {
+        Frozen { set: self }
+    }
+
+    /// drop all values for which `f` returns false.
+    ///
+    /// like with `clear`, entries of removed values that are still alive are left without a value.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let removed: Vec<T> = {
+            let mut inner = self.inner.borrow_mut();
+            let mut removed = Vec::new();
+            let mut cursor = inner.first_used;
+            while let Some(index) = cursor {
+                let keep = match inner.slots[index] {
+                    WeakSetSlot::Used { ref value, links, .. } => {
+                        cursor = links.next;
+                        f(value)
+                    }
+                    WeakSetSlot::Empty { .. } => unreachable!("empty slot in the used list")
+                };
+                if !keep {
+                    removed.extend(inner.free(index));
+                }
+            }
+            // relink, so the lowest free slot is reused first
+            inner.rebuild_free_list();
+            removed
+        };
+        drop(removed);
+    }
+
+    /// like `retain`, but `f` is given an entry, so it can decide based on the slot as well.
+    ///
+    /// the value of a removed entry is dropped even if other entries for it are still alive,
+    /// they are left without a value like with `clear`.
+    pub fn retain_entries<F: FnMut(&WeakSetEntry<T>) -> bool>(&mut self, mut f: F) {
+        // the entries handed to `f` hold a reference themselves, so the set can't be borrowed while `f` runs.
+        // each entry is dropped again before the slot is freed.
+        let remove: Vec<usize> = self.iter()
+            .filter(|entry| !f(entry))
+            .map(|entry| entry.index)
+            .collect();
+
+        let removed: Vec<T> = {
+            let mut inner = self.inner.borrow_mut();
+            // an entry may have been the last one, in which case its slot is already free
+            let removed = remove.into_iter().filter_map(|index| inner.free(index)).collect();
+            inner.rebuild_free_list();
+            removed
+        };
+        drop(removed);
+    }
+
+    // free the given slots, unless they hold a different value by now.
+    // like `retain_entries`, remaining entries of them are left without a value.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn remove_slots(&self, slots: &[(usize, u32)]) {
+        let removed: Vec<T> = {
+            let mut inner = self.inner.borrow_mut();
+            slots.iter()
+                .filter_map(|&(index, generation)| match inner.generation(index) {
+                    Some(g) if g == generation => inner.free(index),
+                    _ => None
+                })
+                .collect()
+        };
+        drop(removed);
+    }
+
+    /// number of live entries in the set
+    pub fn len(&self) -> usize {
+        self.inner.borrow().len
+    }
+
+    /// true if the set holds no live entries
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct WeakSetIter<'a, T> {
+    set: &'a WeakSet<T>,
+
+    // the next slots to visit from either end
+    front: Option<usize>,
+    back: Option<usize>,
+
+    // the generations of the last values yielded from either end.
+    // they increase along the used list, so the ends have met once the next value is not between them.
+    front_generation: Option<u32>,
+    back_generation: Option<u32>,
+
+    // slots from here on were added after the iterator was created
+    end: usize,
+
+    // live entries when the iterator was created, minus the ones yielded since.
+    // entries can be dropped during iteration, so this is only an upper bound.
+    remaining: usize
+}
+impl<'a, T> Iterator for WeakSetIter<'a, T> {
+    type Item = WeakSetEntry<T>;
+    fn next(&mut self) -> Option<WeakSetEntry<T>> {
+        while let Some(index) = self.front.filter(|&index| index < self.end) {
+            let (generation, links) = self.set.inner.borrow().visit_links(index);
+            self.front = links.next;
+            match generation {
+                Some(generation) if self.back_generation.is_some_and(|back| !inserted_before(generation, back)) => break,
+                Some(generation) => {
+                    self.front_generation = Some(generation);
+                    self.remaining = self.remaining.saturating_sub(1);
+                    return self.set.make_checked_entry(index, generation);
+                }
+                None => {}
+            }
+        }
+        self.finish()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
+// follows the used list backwards, so this is in reverse insertion order, see `WeakSet::iter_rev`
+impl<'a, T> DoubleEndedIterator for WeakSetIter<'a, T> {
+    fn next_back(&mut self) -> Option<WeakSetEntry<T>> {
+        while let Some(index) = self.back {
+            let (generation, links) = self.set.inner.borrow().visit_links(index);
+            self.back = links.prev;
+            match generation {
+                Some(generation) if self.front_generation.is_some_and(|front| !inserted_before(front, generation)) => break,
+                Some(generation) => {
+                    self.back_generation = Some(generation);
+                    self.remaining = self.remaining.saturating_sub(1);
+                    return self.set.make_checked_entry(index, generation);
+                }
+                None => {}
+            }
+        }
+        self.finish()
+    }
+}
+
+impl<'a, T> WeakSetIter<'a, T> {
+    // one end reached the other (or the end of the list), so nothing is left on either side
+    fn finish(&mut self) -> Option<WeakSetEntry<T>> {
+        self.front = None;
+        self.back = None;
+        self.remaining = 0;
+        None
+    }
+}
+
+// true if the value of generation `a` was inserted before the one of generation `b`.
+// generations wrap around, so this compares their distance.
+fn inserted_before(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
+impl<'a, T> Drop for WeakSetIter<'a, T> {
+    fn drop(&mut self) {
+        self.set.inner.iterators.set(self.set.inner.iterators.get() - 1);
+    }
+}
+
+// once the end of the used list is reached, `next` stays `None`
+impl<'a, T> FusedIterator for WeakSetIter<'a, T> {}
+
+impl<'a, T> IntoIterator for &'a WeakSet<T> {
+    type Item = WeakSetEntry<T>;
+    type IntoIter = WeakSetIter<'a, T>;
+    fn into_iter(self) -> WeakSetIter<'a, T> {
+        self.iter()
+    }
+}
+
+/// a `WeakSet` that can't be inserted into, see `WeakSet::iter_guarded`
+pub struct Frozen<'a, T> {
+    set: &'a mut WeakSet<T>
+}
+impl<'a, T> Frozen<'a, T> {
+    /// iterate over the entries, see `WeakSet::iter`
+    pub fn iter(&self) -> WeakSetIter<'_, T> {
+        self.set.iter()
+    }
+
+    /// get an entry for the slot at `index`, see `WeakSet::get`
+    pub fn get(&self, index: usize) -> Option<WeakSetEntry<T>> {
+        self.set.get(index)
+    }
+
+    /// number of live entries in the set
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    /// true if the set holds no live entries
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+}
+impl<'a, 'b, T> IntoIterator for &'b Frozen<'a, T> {
+    type Item = WeakSetEntry<T>;
+    type IntoIter = WeakSetIter<'b, T>;
+    fn into_iter(self) -> WeakSetIter<'b, T> {
+        self.iter()
+    }
+}
+
+pub struct WeakSetDrain<'a, T> {
+    set: &'a mut WeakSet<T>
+}
+impl<'a, T> Iterator for WeakSetDrain<'a, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        let mut inner = self.set.inner.borrow_mut();
+        let index = inner.first_used?;
+        inner.free(index)
+    }
+}
+impl<'a, T> Drop for WeakSetDrain<'a, T> {
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
+/// a reference to a slot that does not keep the value alive (it does keep the set alive).
+pub struct WeakEntry<T> {
+    set: WeakSet<T>,
+    index: usize,
+    generation: u32
+}
+
+impl<T> Clone for WeakEntry<T> {
+    fn clone(&self) -> Self {
+        WeakEntry {
+            set: self.set.clone(),
+            index: self.index,
+            generation: self.generation
+        }
+    }
+}
+
+impl<T> WeakEntry<T> {
+    /// get an entry for the value, unless it has been dropped.
+    /// this also returns `None` if the slot has been reused for another value in the meantime.
+    pub fn upgrade(&self) -> Option<WeakSetEntry<T>> {
+        self.set.make_checked_entry(self.index, self.generation)
+    }
+}
+
+impl<T> Drop for WeakSetEntry<T> {
+    fn drop(&mut self) {
+        self.set.drop_entry(self.index, self.generation);
+    }
+}
+
+impl<T> Clone for WeakSetEntry<T> {
+    fn clone(&self) -> Self {
+        match self.set.make_checked_entry(self.index, self.generation) {
+            Some(entry) => entry,
+            // the value was removed (e.g. by `clear`), so the clone is just as inert as this entry
+            None => WeakSetEntry {
+                set: self.set.clone(),
+                index: self.index,
+                generation: self.generation
+            }
+        }
+    }
+}
+
+impl<T> WeakSetEntry<T> {
+    /// the index of the slot this entry refers to.
+    /// it stays the same for the lifetime of the entry, as slots are only moved by `compact`.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// the generation of the value, which tells it apart from other values stored in the same slot.
+    /// see `WeakSet::get_checked`.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// a handle of the set this entry belongs to
+    pub fn set(&self) -> WeakSet<T> {
+        self.set.clone()
+    }
+
+    /// true if both entries refer to the same slot of the same set.
+    /// (comparing the indices alone is not enough, as different sets reuse the same indices)
+    pub fn ptr_eq(&self, other: &WeakSetEntry<T>) -> bool {
+        Rc::ptr_eq(&self.set.inner, &other.set.inner) && self.index == other.index && self.generation == other.generation
+    }
+
+    /// the number of entries referring to this slot (including this one).
+    /// this is 0 if the value was removed from the set (e.g. by `clear`).
+    pub fn refcount(&self) -> usize {
+        self.set.inner.borrow().refcount(self.index, self.generation)
+    }
+
+    /// the same as `refcount`, named like `Rc::strong_count`
+    pub fn strong_count(&self) -> usize {
+        self.refcount()
+    }
+
+    /// create a weak reference to this entry
+    pub fn weak(&self) -> WeakEntry<T> {
+        WeakEntry {
+            set: self.set.clone(),
+            index: self.index,
+            generation: self.generation
+        }
+    }
+
+    /// borrow the value stored in the set
+    pub fn borrow(&self) -> Ref<T> {
+        Ref::map(self.set.inner.borrow(), |inner| inner.slot(self.index, self.generation).unwrap())
+    }
+
+    /// mutably borrow the value stored in the set
+    pub fn borrow_mut(&self) -> RefMut<T> {
+        RefMut::map(self.set.inner.borrow_mut(), |inner| inner.slot_mut(self.index, self.generation).unwrap())
+    }
+
+    /// borrow a part of the value, like `Ref::map`
+    pub fn map<U: ?Sized, F: FnOnce(&T) -> &U>(&self, f: F) -> Ref<'_, U> {
+        Ref::map(self.borrow(), f)
+    }
+
+    /// mutably borrow a part of the value, like `RefMut::map`
+    pub fn map_mut<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(&self, f: F) -> RefMut<'_, U> {
+        RefMut::map(self.borrow_mut(), f)
+    }
+
+    /// borrow the value, failing if the set is currently mutably borrowed.
+    /// gives `Ok(None)` if the value was removed from the set (e.g. by `clear`).
+    pub fn try_borrow(&self) -> Result<Option<Ref<'_, T>>, BorrowError> {
+        self.set.inner.try_borrow()
+        .map(|inner| Ref::filter_map(inner, |inner| inner.slot(self.index, self.generation)).ok())
+    }
+
+    /// mutably borrow the value, failing if the set is currently borrowed.
+    /// gives `Ok(None)` if the value was removed from the set (e.g. by `clear`).
+    pub fn try_borrow_mut(&self) -> Result<Option<RefMut<'_, T>>, BorrowMutError> {
+        self.set.inner.try_borrow_mut()
+        .map(|inner| RefMut::filter_map(inner, |inner| inner.slot_mut(self.index, self.generation)).ok())
+    }
+}
+
+// unsized values (like `str` or `[u8]`) have to be boxed.
+impl<U: ?Sized> WeakSet<Box<U>> {
+    /// box `val` and insert it, e.g. a `&str` or `String` into a `WeakSet<Box<str>>`
+    pub fn insert_unsized<V: Into<Box<U>>>(&mut self, val: V) -> WeakSetEntry<Box<U>> {
+        self.insert(val.into())
+    }
+}
+
+impl<U: ?Sized> WeakSetEntry<Box<U>> {
+    /// borrow the boxed value, e.g. as a `str` for a `WeakSetEntry<Box<str>>`
+    pub fn borrow_unboxed(&self) -> Ref<'_, U> {
+        Ref::map(self.borrow(), |val| &**val)
+    }
+}
+
+impl<T: AsRef<str>> WeakSetEntry<T> {
+    /// borrow the value as a `str`, e.g. for a `WeakSetEntry<Box<str>>` or `WeakSetEntry<String>`
+    pub fn borrow_str(&self) -> Ref<'_, str> {
+        Ref::map(self.borrow(), |val| val.as_ref())
+    }
+}
+
+// the set already allows mutating its values, so a `RefCell` value is usually not needed.
+// if there is one anyway, mutably borrowing the set and the cell at once easily panics.
+impl<U> WeakSetEntry<RefCell<U>> {
+    /// run `f` on the value inside the `RefCell` and return its result.
+    /// the set is only borrowed immutably, so other values can still be read in `f`.
+    ///
+    /// panics if the set is mutably borrowed or the cell is already borrowed.
+    pub fn borrow_twice_mut<R, F: FnOnce(&mut U) -> R>(&self, f: F) -> R {
+        let cell = self.borrow();
+        let mut value = cell.borrow_mut();
+        f(&mut value)
+    }
+}
+
+// a set of values of any type, which can be downcast when borrowed.
+impl WeakSet<Box<dyn Any>> {
+    /// box `val` and insert it
+    pub fn insert_boxed<U: 'static>(&mut self, val: U) -> WeakSetEntry<Box<dyn Any>> {
+        self.insert(Box::new(val))
+    }
+}
+
+impl WeakSetEntry<Box<dyn Any>> {
+    /// borrow the value if it is a `U`
+    pub fn downcast_ref<U: 'static>(&self) -> Option<Ref<'_, U>> {
+        Ref::filter_map(self.borrow(), |val| val.downcast_ref()).ok()
+    }
+
+    /// mutably borrow the value if it is a `U`
+    pub fn downcast_mut<U: 'static>(&self) -> Option<RefMut<'_, U>> {
+        RefMut::filter_map(self.borrow_mut(), |val| val.downcast_mut()).ok()
+    }
+}
+
+/// entries compare by identity, not by value:
+/// they are equal if they refer to the same slot of the same set (see `ptr_eq`).
+impl<T> PartialEq for WeakSetEntry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other)
+    }
+}
+// identity is an equivalence relation, even if `T` isn't `Eq`
+impl<T> Eq for WeakSetEntry<T> {}
+
+// consistent with `PartialEq`: hashes the identity of the set and the slot index
+impl<T> Hash for WeakSetEntry<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.set.inner).hash(state);
+        self.index.hash(state);
+    }
+}
+
+/// sets compare by their live values, regardless of order and the slots they are in.
+/// (so handles of the same set are always equal)
+///
+/// `T` only needs `PartialEq`, so this takes O(n²).
+impl<T: PartialEq> PartialEq for WeakSet<T> {
+    fn eq(&self, other: &Self) -> bool {
+        if Rc::ptr_eq(&self.inner, &other.inner) {
+            return true;
+        }
+        let (a, b) = (self.inner.borrow(), other.inner.borrow());
+        if a.len != b.len {
+            return false;
+        }
+
+        // match every value of `a` against a distinct value of `b`
+        let mut matched = alloc::vec![false; b.len];
+        let eq = a.values().all(|x| {
+            let found = b.values().zip(matched.iter_mut()).find(|(y, m)| !**m && x == *y);
+            match found {
+                Some((_, m)) => {
+                    *m = true;
+                    true
+                }
+                None => false
+            }
+        });
+        eq
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for WeakSet<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // this debug impl does not create references. 
+        // we can do this because we control the iterator and can be sure nothing will try to borrow the RefCell during iteration
+
+        let inner = self.inner.borrow();
+        f.debug_set().entries(inner.slots.iter()).finish()
+    }
+}
+
+// serializes the live values as a sequence
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for WeakSet<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.inner.borrow().values())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for WeakSetEntry<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // this may be called while the set is borrowed (e.g. in the middle of a `borrow_mut`), so don't panic.
+        let mut s = f.debug_struct("WeakSetEntry");
+        s.field("index", &self.index);
+        match self.set.inner.try_borrow() {
+            Ok(inner) => match inner.slot(self.index, self.generation) {
+                Some(value) => s.field("value", value).finish(),
+                None => s.field("value", &format_args!("<empty>")).finish()
+            },
+            Err(_) => s.field("value", &format_args!("<borrowed>")).finish()
+        }
+    }
+}
+
+/// the reason a fallible operation failed.
+/// operations that take a value return it along with the error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WeakSetError {
+    /// the set holds as many entries as `WeakSet::with_max_capacity` allows
+    CapacityExceeded
+}
+impl fmt::Display for WeakSetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WeakSetError::CapacityExceeded => write!(f, "the WeakSet is at its maximum capacity")
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for WeakSetError {}
+
+/// counters of the work done by `insert`, see `WeakSet::metrics`
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WeakSetMetrics {
+    /// number of existing slots that were looked at to find a free one
+    pub slots_scanned: u64,
+
+    /// number of times the slots had to be reallocated to add one
+    pub growths: u64
+}
+
+impl<T: fmt::Debug> fmt::Debug for WeakSetSlot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WeakSetSlot::Empty { .. } => write!(f, "empty"),
+            WeakSetSlot::Used { ref value, refcount, .. } => write!(f, "{:?}({})", value, refcount)
+        }
+    }
+}
+
+// the values of `set` in iteration order
+#[cfg(test)]
+fn values<T: Copy>(set: &WeakSet<T>) -> Vec<T> {
+    set.iter().map(|e| *e.borrow()).collect()
+}
+
+#[test]
+fn test_set() {
+    let mut set = WeakSet::new();
+    let _0 = set.insert("hello world!");
+    println!("set: {:?}", set);
+    let _1 = set.insert("hello luna!");
+    println!("set: {:?}", set);
+
+    drop(_0);
+    println!("set: {:?}", set);
+
+    let _2 = set.insert("hello enso!");
+    println!("set: {:?}", set);
+
+    let _3 = _2.clone();
+    println!("set: {:?}", set);
+}
+
+#[test]
+fn test_len() {
+    let mut set = WeakSet::new();
+    assert!(set.is_empty());
+
+    let a = set.insert(0);
+    let b = set.insert(1);
+    let c = set.insert(2);
+    assert_eq!(set.len(), 3);
+
+    drop(b);
+    assert_eq!(set.len(), 2);
+
+    drop(a);
+    drop(c);
+    assert_eq!(set.len(), 0);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_capacity() {
+    let mut set = WeakSet::with_capacity(16);
+    assert!(set.capacity() >= 16);
+
+    let entries: Vec<_> = (0 .. 16).map(|i| set.insert(i)).collect();
+    assert!(set.capacity() >= 16);
+    assert_eq!(set.len(), entries.len());
+}
+
+#[test]
+fn test_get() {
+    let mut set = WeakSet::new();
+    let a = set.insert("a");
+    let b = set.insert("b");
+    drop(a);
+
+    assert!(set.get(0).is_none());
+    assert_eq!(*set.get(1).unwrap().borrow(), "b");
+    assert!(set.get(2).is_none());
+    drop(b);
+}
+
+#[test]
+fn test_index() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+    assert_eq!(a.index(), 0);
+    assert_eq!(b.index(), 1);
+
+    let b2 = set.get(b.index()).unwrap();
+    assert_eq!(*b2.borrow(), 2);
+}
+
+#[test]
+fn test_clear() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+
+    set.clear();
+    assert!(set.is_empty());
+
+    // dropping entries of a cleared set is fine
+    drop(a);
+    drop(b);
+    assert!(set.is_empty());
+
+    let _c = set.insert(3);
+    assert_eq!(set.len(), 1);
+}
+
+#[test]
+fn test_clear_reused_slot() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    set.clear();
+
+    // the slot of `a` is reused, but dropping `a` leaves the new value alone
+    let b = set.insert(2);
+    assert_eq!(a.index(), b.index());
+    drop(a);
+    assert_eq!(set.len(), 1);
+    assert_eq!(*b.borrow(), 2);
+}
+
+#[test]
+fn test_drop_emptied_entry() {
+    let mut set = WeakSet::new();
+    let _a = set.insert(1);
+    let b = set.insert(2);
+
+    // empty the slot behind the back of `b`
+    assert_eq!(set.inner.borrow_mut().free(1), Some(2));
+
+    // it must not end up on the free list twice
+    drop(b);
+    assert_eq!(set.inner.borrow().first_free, Some(1));
+    assert!(matches!(set.inner.borrow().slots[1], WeakSetSlot::Empty { next_free: None, .. }));
+    assert_eq!(set.len(), 1);
+}
+
+#[test]
+fn test_retain() {
+    let mut set = WeakSet::new();
+    let entries: Vec<_> = (0 .. 5).map(|i| set.insert(i)).collect();
+
+    set.retain(|&i| i % 2 == 0);
+    assert_eq!(values(&set), [0, 2, 4]);
+    assert_eq!(set.inner.borrow().first_free, Some(1));
+
+    drop(entries);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_into_iter() {
+    let mut set = WeakSet::new();
+    let _entries: Vec<_> = (1 ..= 3).map(|i| set.insert(i)).collect();
+
+    let mut sum = 0;
+    for entry in &set {
+        sum += *entry.borrow();
+    }
+    assert_eq!(sum, 6);
+}
+
+#[test]
+fn test_iter_len() {
+    let mut set = WeakSet::new();
+    let _a = set.insert(1);
+    let _b = set.insert(2);
+    let c = set.insert(3);
+
+    let mut iter = set.iter();
+    assert_eq!(iter.size_hint(), (0, Some(3)));
+
+    iter.next();
+    assert_eq!(iter.size_hint(), (0, Some(2)));
+
+    // dropping an entry mid-iteration leaves the upper bound in place
+    drop(c);
+    assert_eq!(iter.size_hint(), (0, Some(2)));
+    assert_eq!(iter.count(), 1);
+}
+
+#[test]
+fn test_try_borrow() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+
+    {
+        let _guard = a.borrow_mut();
+        assert!(b.try_borrow().is_err());
+        assert!(b.try_borrow_mut().is_err());
+    }
+    {
+        let _guard = a.borrow();
+        assert_eq!(*b.try_borrow().unwrap().unwrap(), 2);
+        assert!(b.try_borrow_mut().is_err());
+    }
+    *b.try_borrow_mut().unwrap().unwrap() = 3;
+    assert_eq!(*b.borrow(), 3);
+
+    // an orphaned entry has nothing to borrow
+    set.clear();
+    assert!(b.try_borrow().unwrap().is_none());
+    assert!(b.try_borrow_mut().unwrap().is_none());
+}
+
+#[test]
+fn test_free_list() {
+    let mut set = WeakSet::new();
+    let mut entries: Vec<_> = (0 .. 1000).map(|i| Some(set.insert(i))).collect();
+
+    // punch a hole into every other slot
+    for entry in entries.iter_mut().step_by(2) {
+        *entry = None;
+    }
+    assert_eq!(set.len(), 500);
+
+    // the holes are reused most recently freed first, without growing the slots
+    for (n, hole) in (0 .. 1000).step_by(2).rev().enumerate() {
+        let entry = set.insert(1000 + n);
+        assert_eq!(entry.index(), hole);
+        entries[hole] = Some(entry);
+    }
+    assert_eq!(set.len(), 1000);
+    assert_eq!(set.inner.borrow().slots.len(), 1000);
+    assert_eq!(set.inner.borrow().first_free, None);
+}
+
+#[test]
+fn test_used_list() {
+    let mut set = WeakSet::new();
+    let mut entries: Vec<_> = (0 .. 100).map(|i| Some(set.insert(i))).collect();
+
+    // keep only a few entries alive
+    for (i, entry) in entries.iter_mut().enumerate() {
+        if i % 25 != 0 {
+            *entry = None;
+        }
+    }
+    assert_eq!(values(&set), [0, 25, 50, 75]);
+
+    // drop the current and the next entry while iterating
+    let mut iter = set.iter();
+    let first = iter.next().unwrap();
+    assert_eq!(*first.borrow(), 0);
+    drop(first);
+    entries[0] = None;
+    entries[25] = None;
+    let rest: Vec<i32> = iter.map(|e| *e.borrow()).collect();
+    assert_eq!(rest, [50, 75]);
+
+    // reused slots are visited in insertion order
+    let late = set.insert(100);
+    assert_eq!(late.index(), 25);
+    assert_eq!(values(&set), [50, 75, 100]);
+}
+
+#[test]
+fn test_entry_ptr_eq() {
+    let mut set = WeakSet::new();
+    let mut other = WeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(1);
+    let c = other.insert(1);
+
+    assert!(WeakSetEntry::ptr_eq(&a, &a.clone()));
+    assert!(!a.ptr_eq(&b));
+
+    // same index, different set
+    assert_eq!(a.index(), c.index());
+    assert!(!a.ptr_eq(&c));
+}
+
+#[test]
+fn test_entry_eq() {
+    let mut set = WeakSet::new();
+    let a = set.insert("same");
+    let b = set.insert("same");
+
+    assert!(a == a.clone());
+    assert!(a != b);
+}
+
+#[test]
+fn test_entry_hash() {
+    use std::collections::hash_map::DefaultHasher;
+    fn hash_of<T>(entry: &WeakSetEntry<T>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        entry.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(1);
+    assert_eq!(hash_of(&a), hash_of(&a.clone()));
+    assert_ne!(hash_of(&a), hash_of(&b));
+}
+
+#[test]
+fn test_weak_entry() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let weak = a.weak();
+    assert_eq!(set.len(), 1);
+
+    let a2 = weak.upgrade().unwrap();
+    assert!(a == a2);
+    drop(a);
+    drop(a2);
+
+    // the weak reference did not keep the value alive
+    assert!(set.is_empty());
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_refcount() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    assert_eq!(a.refcount(), 1);
+
+    let b = a.clone();
+    let c = set.iter().next().unwrap();
+    assert_eq!(a.refcount(), 3);
+
+    drop(b);
+    drop(c);
+    assert_eq!(a.refcount(), 1);
+
+    set.clear();
+    assert_eq!(a.refcount(), 0);
+}
+
+#[test]
+fn test_borrow_twice_mut() {
+    let mut set = WeakSet::new();
+    let a = set.insert(RefCell::new(vec![1]));
+    let b = set.insert(RefCell::new(vec![2]));
+
+    let len = a.borrow_twice_mut(|v| {
+        v.push(b.borrow().borrow()[0]);
+        v.len()
+    });
+    assert_eq!(len, 2);
+    assert_eq!(*a.borrow().borrow(), [1, 2]);
+}
+
+#[test]
+fn test_strong_count() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let b = a.clone();
+    assert_eq!(a.strong_count(), 2);
+
+    // weak entries don't count
+    let weak = a.weak();
+    drop(b);
+    assert_eq!(a.strong_count(), 1);
+
+    drop(a);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+#[should_panic(expected = "refcount overflow")]
+fn test_refcount_overflow() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    if let WeakSetSlot::Used { ref mut refcount, .. } = set.inner.borrow_mut().slots[0] {
+        *refcount = usize::MAX;
+    }
+    let _b = a.clone();
+}
+
+#[test]
+fn test_iter_indices() {
+    let mut set = WeakSet::new();
+    let a = set.insert("a");
+    let _b = set.insert("b");
+    let _c = set.insert("c");
+    drop(a);
+
+    let pairs: Vec<(usize, &str)> = set.iter_indices().map(|(i, e)| (i, *e.borrow())).collect();
+    assert_eq!(pairs, [(1, "b"), (2, "c")]);
+}
+
+#[test]
+fn test_from_iter_entries() {
+    let (set, entries) = WeakSet::from_iter_entries(vec!["a", "b", "c"]);
+    assert_eq!(set.len(), 3);
+    assert_eq!(*entries[1].borrow(), "b");
+
+    drop(entries);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_share() {
+    let mut set = WeakSet::new();
+    let shared = set.share();
+    let cloned = set.clone();
+    assert!(set.ptr_eq(&shared));
+    assert!(shared.ptr_eq(&cloned));
+
+    let _a = set.insert(1);
+    assert_eq!(cloned.len(), 1);
+
+    let (copy, _entries) = set.deep_clone();
+    assert!(!copy.ptr_eq(&set));
+}
+
+#[test]
+fn test_iter_rev() {
+    let mut set = WeakSet::new();
+    let mut entries: Vec<_> = (0 .. 5).map(|i| set.insert(i)).collect();
+
+    // reuse a slot, so the insertion order differs from the slot order
+    entries.remove(1);
+    entries.push(set.insert(5));
+
+    let forward = values(&set);
+    let mut backward: Vec<i32> = set.iter_rev().map(|e| *e.borrow()).collect();
+    assert_eq!(backward, [5, 4, 3, 2, 0]);
+    backward.reverse();
+    assert_eq!(forward, backward);
+
+    // entries dropped during iteration are skipped, and new ones are not visited
+    let mut iter = set.iter_rev();
+    assert_eq!(*iter.next().unwrap().borrow(), 5);
+    entries.remove(3);
+    let _new = set.insert_shared(6);
+    assert_eq!(iter.map(|e| *e.borrow()).collect::<Vec<_>>(), [3, 2, 0]);
+
+    // both ends can be used, and stop where they meet, even if the value between them is dropped
+    let mut set = WeakSet::new();
+    let mut entries = set.extend_entries(0 .. 4);
+    let mut iter = set.iter();
+    assert_eq!(*iter.next_back().unwrap().borrow(), 3);
+    assert_eq!(*iter.next().unwrap().borrow(), 0);
+    entries.remove(2);
+    assert_eq!(*iter.next().unwrap().borrow(), 1);
+    assert!(iter.next().is_none());
+    assert!(iter.next_back().is_none());
+}
+
+#[test]
+fn test_clear_and_collect() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+    let _c = set.insert(3);
+    drop(b);
+
+    assert_eq!(set.clear_and_collect(), [1, 3]);
+    assert!(set.is_empty());
+    assert_eq!(set.first_free_index(), Some(0));
+
+    // the entry is left without a value, and dropping it does not touch the reused slot
+    let d = set.insert(4);
+    assert_eq!(d.index(), 0);
+    drop(a);
+    assert_eq!(*d.borrow(), 4);
+}
+
+#[test]
+fn test_insert_with() {
+    struct Node {
+        id: usize
+    }
+
+    let mut set = WeakSet::new();
+    let a = set.insert_with(|id| Node { id });
+    let b = set.insert_with(|id| Node { id });
+    assert_eq!(a.borrow().id, a.index());
+    assert_eq!(b.borrow().id, 1);
+
+    // the freed slot is reused, and the value knows
+    drop(a);
+    let c = set.insert_with(|id| Node { id });
+    assert_eq!(c.borrow().id, 0);
+    assert_eq!(c.index(), 0);
+}
+
+#[test]
+fn test_from_vec() {
+    let (set, entries) = WeakSet::from_vec(vec!["a", "b", "c"]);
+    assert_eq!(set.capacity(), 3);
+    assert_eq!(entries.capacity(), 3);
+    assert_eq!(entries.iter().map(|e| *e.borrow()).collect::<Vec<_>>(), ["a", "b", "c"]);
+    assert_eq!(entries.iter().map(|e| e.index()).collect::<Vec<_>>(), [0, 1, 2]);
+    assert_eq!(set.first_free_index(), None);
+}
+
+#[test]
+fn test_swap_remove() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+    let c = set.insert(3);
+
+    // other entries of the value keep it in the set
+    let b2 = b.clone();
+    assert_eq!(set.swap_remove(&b), None);
+    drop(b2);
+
+    assert_eq!(set.swap_remove(&b), Some(2));
+    assert_eq!(set.len(), 2);
+    assert_eq!(set.first_free_index(), Some(1));
+    drop(b);
+
+    // removing the last value drops the empty slots at the end
+    assert_eq!(set.swap_remove(&c), Some(3));
+    assert_eq!(set.slot_count(), 1);
+    drop(c);
+
+    let d = set.insert(4);
+    assert_eq!(d.index(), 1);
+    assert_eq!(set.to_vec(), [1, 4]);
+    drop(a);
+}
+
+#[test]
+fn test_iter_values() {
+    let mut set = WeakSet::new();
+    let mut entries: Vec<_> = (1..=4).map(|i| set.insert(i)).collect();
+
+    // all refs can be held at once
+    let refs: Vec<_> = set.iter_values().collect();
+    assert_eq!(refs.iter().map(|r| **r).sum::<i32>(), 10);
+    drop(refs);
+
+    entries.remove(1);
+    assert_eq!(set.iter_values().map(|r| *r).collect::<Vec<_>>(), [1, 3, 4]);
+}
+
+#[test]
+fn test_set_ptr_eq() {
+    let a: WeakSet<i32> = WeakSet::new();
+    let b = a.clone();
+    let c = WeakSet::new();
+    assert!(WeakSet::ptr_eq(&a, &b));
+    assert!(!WeakSet::ptr_eq(&a, &c));
+}
+
+#[test]
+fn test_shrink_to() {
+    let mut set = WeakSet::with_capacity(64);
+    let entries: Vec<_> = (0..20).map(|i| set.insert(i)).collect();
+    let last = entries.last().unwrap().clone();
+    drop(entries);
+
+    // slot 19 is still used, so only the capacity after it can go
+    set.shrink_to(4);
+    assert_eq!(set.slot_count(), 20);
+    assert!(set.capacity() >= 20 && set.capacity() < 64);
+    assert_eq!(*last.borrow(), 19);
+
+    drop(last);
+    set.shrink_to(4);
+    assert_eq!(set.slot_count(), 0);
+    assert!(set.capacity() >= 4);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_metrics() {
+    let mut set = WeakSet::with_capacity(4);
+    let entries: Vec<_> = (0..4).map(|i| set.insert(i)).collect();
+    assert_eq!(set.metrics(), WeakSetMetrics { slots_scanned: 0, growths: 0 });
+
+    // a fragmented set reuses the free slots
+    drop(entries);
+    let _a = set.insert(4);
+    let _b = set.insert(5);
+    assert_eq!(set.metrics().slots_scanned, 2);
+    assert_eq!(set.metrics().growths, 0);
+
+    let _more: Vec<_> = (0..3).map(|i| set.insert(i)).collect();
+    assert_eq!(set.metrics().slots_scanned, 4);
+    assert_eq!(set.metrics().growths, 1);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serialize() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let _b = set.insert(2);
+    let _c = set.insert(3);
+    drop(a);
+
+    assert_eq!(serde_json::to_string(&set).unwrap(), "[2,3]");
+}
+
+#[test]
+fn test_drain() {
+    let mut set = WeakSet::new();
+    let a = set.insert(String::from("a"));
+    let _b = set.insert(String::from("b"));
+    let _c = set.insert(String::from("c"));
+
+    assert_eq!(set.drain().next().unwrap(), "a");
+    assert!(set.is_empty());
+    assert!(set.drain().next().is_none());
+
+    // a's value has been moved out
+    assert_eq!(a.refcount(), 0);
+    drop(a);
+
+    let _d = set.insert(String::from("d"));
+    let _e = set.insert(String::from("e"));
+    let values: Vec<String> = set.drain().collect();
+    assert_eq!(values, ["d", "e"]);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_shrink_to_fit() {
+    let mut set = WeakSet::new();
+    set.reserve(100);
+    assert!(set.capacity() >= 100);
+
+    let mut entries: Vec<_> = (0 .. 100).map(|i| set.insert(i)).collect();
+    let hole = entries.remove(10);
+    drop(hole);
+    entries.truncate(19);
+
+    set.shrink_to_fit();
+    assert!(set.capacity() < 100);
+    assert_eq!(set.inner.borrow().slots.len(), 20);
+
+    // the hole is still reused first, but the trimmed slots are not
+    let a = set.insert(100);
+    let b = set.insert(101);
+    assert_eq!(a.index(), 10);
+    assert_eq!(b.index(), 20);
+}
+
+#[test]
+fn test_entry_debug() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    assert_eq!(format!("{:?}", a), "WeakSetEntry { index: 0, value: 1 }");
+
+    let guard = a.borrow_mut();
+    assert_eq!(format!("{:?}", a), "WeakSetEntry { index: 0, value: <borrowed> }");
+    drop(guard);
+
+    set.clear();
+    assert_eq!(format!("{:?}", a), "WeakSetEntry { index: 0, value: <empty> }");
+}
+
+#[test]
+fn test_with_mut() {
+    let mut set = WeakSet::new();
+    let a = set.insert(String::from("hello"));
+
+    let len = set.with_mut(&a, |s| {
+        s.push_str(" world");
+        s.len()
+    });
+    assert_eq!(len, 11);
+    assert_eq!(*a.borrow(), "hello world");
+
+    // the borrow has ended, so the set can be used again
+    let b = set.insert(String::new());
+    assert_eq!(set.len(), 2);
+    drop(b);
+}
+
+#[test]
+#[should_panic(expected = "different set")]
+fn test_with_mut_other_set() {
+    let set = WeakSet::new();
+    let mut other = WeakSet::new();
+    let a = other.insert(1);
+    set.with_mut(&a, |v| *v += 1);
+}
+
+#[test]
+fn test_iter_fused() {
+    let mut set = WeakSet::new();
+    let _a = set.insert(1);
+
+    let mut iter = set.iter();
+    assert!(iter.next().is_some());
+    assert!(iter.next().is_none());
+
+    // values inserted after the end was reached are not picked up
+    let _b = set.clone().insert(2);
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn test_iter_while_borrowed() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+
+    // iterators can be created and dropped while a value is borrowed
+    let guard = a.borrow();
+    let mut iter = set.iter();
+    drop(guard);
+    assert_eq!(*iter.next().unwrap().borrow(), 1);
+    {
+        let _guard = a.borrow_mut();
+        drop(iter);
+    }
+
+    // no iterator is registered any more, so the freed slot is reused
+    let index = b.index();
+    drop(b);
+    assert_eq!(set.insert(3).index(), index);
+}
+
+#[test]
+fn test_shrink_while_iterating() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+    let c = set.insert(3);
+
+    let mut iter = set.iter();
+    let first = iter.next().unwrap();
+
+    // drop the tail and try to shrink through another handle
+    drop(b);
+    drop(c);
+    set.clone().shrink_to_fit();
+    assert_eq!(set.inner.borrow().slots.len(), 3);
+
+    assert!(iter.next().is_none());
+    drop(iter);
+    assert_eq!(*first.borrow(), 1);
+
+    // nothing is iterating any more, so the slots can go now
+    drop(first);
+    set.shrink_to_fit();
+    assert_eq!(set.inner.borrow().slots.len(), 1);
+    assert!(set.get(2).is_none());
+    drop(a);
+}
+
+#[test]
+fn test_find() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let b = set.insert(2);
+    let _c = set.insert(4);
+
+    let even = set.find(|&v| v % 2 == 0).unwrap();
+    assert!(even.ptr_eq(&b));
+    assert_eq!(b.refcount(), 2);
+    assert!(set.find(|&v| v > 10).is_none());
+
+    // the set is not borrowed by the returned entry
+    *even.borrow_mut() = 3;
+    assert_eq!(*set.find(|&v| v % 2 == 0).unwrap().borrow(), 4);
+    drop(a);
+}
+
+#[test]
+fn test_retain_entries() {
+    let mut set = WeakSet::new();
+    let entries: Vec<_> = (0 .. 5).map(|i| set.insert(i * 10)).collect();
+
+    set.retain_entries(|entry| entry.index() % 2 == 1);
+    assert_eq!(values(&set), [10, 30]);
+    assert_eq!(set.len(), 2);
+    assert_eq!(entries[1].refcount(), 1);
+
+    // the removed entries are left without a value
+    assert_eq!(entries[0].refcount(), 0);
+    assert_eq!(set.inner.borrow().first_free, Some(0));
+
+    drop(entries);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_to_vec() {
+    let mut set: WeakSet<String> = WeakSet::new();
+    assert!(set.to_vec().is_empty());
+
+    let a = set.insert("a".into());
+    let b = set.insert("b".into());
+    let c = set.insert("c".into());
+    drop(b);
+    assert_eq!(set.to_vec(), ["a", "c"]);
+
+    // the snapshot is independent of the set
+    a.borrow_mut().push('!');
+    assert_eq!(set.to_vec(), ["a!", "c"]);
+    drop(c);
+}
+
+#[test]
+fn test_extend_entries() {
+    let mut set = WeakSet::new();
+    let a = set.insert(0);
+    let entries = set.extend_entries(1 .. 6);
+    assert_eq!(entries.len(), 5);
+    assert_eq!(set.len(), 6);
+    assert_eq!(*entries[4].borrow(), 5);
+
+    drop(entries);
+    assert_eq!(set.len(), 1);
+    drop(a);
+}
+
+#[test]
+fn test_insert_boxed() {
+    let mut set: WeakSet<Box<dyn Any>> = WeakSet::new();
+    let a = set.insert_boxed(1i32);
+    let b = set.insert_boxed(String::from("b"));
+
+    assert_eq!(*a.downcast_ref::<i32>().unwrap(), 1);
+    assert!(a.downcast_ref::<String>().is_none());
+
+    b.downcast_mut::<String>().unwrap().push('!');
+    assert_eq!(*b.downcast_ref::<String>().unwrap(), "b!");
+    assert!(b.downcast_mut::<i32>().is_none());
+
+    // a failed downcast releases the borrow
+    assert!(b.try_borrow_mut().unwrap().is_some());
+}
+
+#[test]
+fn test_for_each() {
+    struct Listener {
+        name: &'static str,
+        received: Vec<u32>
+    }
+
+    let mut listeners = WeakSet::new();
+    let a = listeners.insert(Listener { name: "a", received: vec![] });
+    let b = listeners.insert(Listener { name: "b", received: vec![] });
+    let c = listeners.insert(Listener { name: "c", received: vec![] });
+
+    let mut notified = vec![];
+    listeners.for_each(|l| notified.push(l.name));
+    assert_eq!(notified, ["a", "b", "c"]);
+
+    // b unsubscribes by dropping its entry
+    drop(b);
+    for event in 0 .. 2 {
+        listeners.iter().for_each(|l| l.borrow_mut().received.push(event));
+    }
+    let mut notified = vec![];
+    listeners.for_each(|l| notified.push((l.name, l.received.len())));
+    assert_eq!(notified, [("a", 2), ("c", 2)]);
+    drop((a, c));
+}
+
+#[test]
+fn test_max_capacity() {
+    let mut set = WeakSet::with_max_capacity(2);
+    let a = set.try_insert(1).unwrap();
+    let _b = set.try_insert(2).unwrap();
+    assert_eq!(set.try_insert(3).unwrap_err(), (WeakSetError::CapacityExceeded, 3));
+    assert_eq!(WeakSetError::CapacityExceeded.to_string(), "the WeakSet is at its maximum capacity");
+    assert_eq!(set.len(), 2);
+
+    // dropping an entry makes room again
+    drop(a);
+    let c = set.try_insert(3).unwrap();
+    assert_eq!(c.index(), 0);
+}
+
+#[test]
+#[should_panic(expected = "maximum capacity")]
+fn test_max_capacity_insert() {
+    let mut set = WeakSet::with_max_capacity(0);
+    set.insert(1);
+}
+
+#[test]
+fn test_compact() {
+    let mut set = WeakSet::new();
+    let mut entries = set.extend_entries(0 .. 6);
+    let second = entries[3].clone();
+    entries.push(second);
+
+    // free 0, 2 and 4
+    entries.retain(|e| *e.borrow() % 2 == 1);
+    let map = set.compact(&mut entries);
+    assert_eq!(map, [None, Some(0), None, Some(1), None, Some(2)]);
+
+    assert_eq!(set.inner.borrow().slots.len(), 3);
+    let indices: Vec<usize> = entries.iter().map(|e| e.index()).collect();
+    assert_eq!(indices, [0, 1, 2, 1]);
+    assert_eq!(set.to_vec(), [1, 3, 5]);
+    assert_eq!(entries[1].refcount(), 2);
+
+    // the set works as usual afterwards
+    entries.remove(0);
+    let a = set.insert(6);
+    assert_eq!(a.index(), 0);
+    assert_eq!(set.to_vec(), [3, 5, 6]);
+}
+
+#[test]
+#[should_panic(expected = "all entries")]
+fn test_compact_missing_entry() {
+    let mut set = WeakSet::new();
+    let mut entries = set.extend_entries(0 .. 3);
+    let _a = entries.pop();
+    set.compact(&mut entries);
+}
+
+#[test]
+fn test_generation() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    let (index, generation) = (a.index(), a.generation());
+    let weak = a.weak();
+    assert!(set.get_checked(index, generation).unwrap().ptr_eq(&a));
+    drop(a);
+
+    // the slot is reused for a new value
+    let b = set.insert(2);
+    assert_eq!(b.index(), index);
+    assert_ne!(b.generation(), generation);
+    assert!(set.get_checked(index, generation).is_none());
+    assert!(set.get_checked(index, b.generation()).is_some());
+    assert!(weak.upgrade().is_none());
+
+    // the plain index finds whatever is stored there
+    assert_eq!(*set.get(index).unwrap().borrow(), 2);
+}
+
+#[test]
+fn test_stale_entry() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    set.clear();
+    let b = set.insert(2);
+    assert_eq!(a.index(), b.index());
+
+    // the cleared entry does not see or release the new value
+    assert_eq!(a.refcount(), 0);
+    assert!(a.ptr_eq(&a) && !a.ptr_eq(&b));
+    assert_eq!(format!("{:?}", a), "WeakSetEntry { index: 0, value: <empty> }");
+    drop(a);
+    assert_eq!(b.refcount(), 1);
+    assert_eq!(*b.borrow(), 2);
+}
+
+#[test]
+fn test_clone_stale_entry() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+    set.clear();
+
+    // cloning an orphaned entry gives another orphaned entry
+    let a2 = a.clone();
+    assert!(a2.ptr_eq(&a));
+    assert_eq!(a2.refcount(), 0);
+
+    let b = set.insert(2);
+    let a3 = a2.clone();
+    drop(a);
+    drop(a2);
+    drop(a3);
+    assert_eq!(b.refcount(), 1);
+    assert_eq!(*b.borrow(), 2);
+}
+
+#[test]
+fn test_for_each_mut() {
+    let mut set = WeakSet::new();
+    let entries = set.extend_entries(0 .. 3);
+    set.for_each_mut(|v| *v += 1);
+
+    let values: Vec<i32> = entries.iter().map(|e| *e.borrow()).collect();
+    assert_eq!(values, [1, 2, 3]);
+}
+
+#[test]
+fn test_set_eq() {
+    let mut a = WeakSet::new();
+    let mut b = WeakSet::new();
+    let _x = a.extend_entries([1, 2, 2]);
+    let mut y = b.extend_entries([0, 2, 1, 2]);
+    assert!(a != b);
+
+    // order and slots don't matter, but the number of equal values does
+    y.remove(0);
+    assert!(a == b);
+    let _z = b.insert(1);
+    assert!(a != b);
+
+    assert!(a == a.clone());
+}
+
+#[test]
+fn test_replace() {
+    let mut set = WeakSet::new();
+    let a = set.insert(String::from("old"));
+    let b = a.clone();
+
+    assert_eq!(set.replace(&a, String::from("new")), "old");
+    assert_eq!(*b.borrow(), "new");
+    assert_eq!(b.index(), a.index());
+    assert_eq!(a.refcount(), 2);
+}
+
+#[test]
+fn test_insert_shared() {
+    let set = WeakSet::new();
+    let a = set.insert_shared(1);
+    let b = set.insert_shared(2);
+
+    // values inserted while iterating are not visited
+    let mut added = vec![];
+    for entry in set.iter() {
+        let val = *entry.borrow() * 10;
+        added.push(set.insert_shared(val));
+    }
+    assert_eq!(set.to_vec(), [1, 2, 10, 20]);
+
+    // even if the previously last value is dropped in between
+    let mut iter = set.iter();
+    let first = iter.next().unwrap();
+    drop(added);
+    let _c = set.insert_shared(3);
+    assert!(iter.next().unwrap().ptr_eq(&b));
+    assert!(iter.next().is_none());
+    drop((first, a));
+}
+
+#[test]
+fn test_iter_guarded() {
+    let mut set = WeakSet::new();
+    let entries = set.extend_entries(0 .. 3);
+
+    {
+        let frozen = set.iter_guarded();
+        // `set.insert_shared(..)` would not compile here
+        let values: Vec<i32> = frozen.iter().map(|e| *e.borrow()).collect();
+        assert_eq!(values, [0, 1, 2]);
+        assert_eq!(frozen.len(), 3);
+        assert!((&frozen).into_iter().next().unwrap().ptr_eq(&entries[0]));
+    }
+
+    // the guard is gone, so the set can be inserted into again
+    let _a = set.insert_shared(3);
+    assert_eq!(set.len(), 4);
+}
+
+#[test]
+fn test_fragmentation() {
+    let mut set = WeakSet::new();
+    assert_eq!(set.fragmentation(), 0.0);
+    assert_eq!(set.first_free_index(), None);
+
+    let mut entries = set.extend_entries(0 .. 4);
+    assert_eq!(set.fragmentation(), 0.0);
+    entries.remove(2);
+    entries.remove(0);
+    assert_eq!(set.fragmentation(), 0.5);
+    assert_eq!(set.first_free_index(), Some(0));
+
+    drop(entries);
+    assert_eq!(set.fragmentation(), 1.0);
+    set.shrink_to_fit();
+    assert_eq!(set.fragmentation(), 0.0);
+}
+
+#[test]
+fn test_with_free_slots() {
+    let mut set = WeakSet::with_free_slots(3);
+    assert!(set.is_empty());
+    assert_eq!(set.first_free_index(), Some(0));
+
+    let entries = set.extend_entries(0 .. 4);
+    let indices: Vec<usize> = entries.iter().map(|e| e.index()).collect();
+    assert_eq!(indices, [0, 1, 2, 3]);
+    assert_eq!(set.inner.borrow().slots.len(), 4);
+    assert_eq!(set.to_vec(), [0, 1, 2, 3]);
+}
+
+#[test]
+fn test_entry_set() {
+    let mut set = WeakSet::new();
+    let a = set.insert(1);
+
+    let mut handle = a.set();
+    let b = handle.insert(2);
+    assert_eq!(set.len(), 2);
+    assert_eq!(a.set().len(), 2);
+    assert!(a.set() == set);
+    drop(b);
+}
+
+#[test]
+// the hash and equality of entries don't depend on the `RefCell` contents
+#[allow(clippy::mutable_key_type)]
+fn test_entry_hash_set() {
+    use std::collections::HashSet;
+
+    let mut set = WeakSet::new();
+    let a = set.insert(f32::NAN);
+    let b = set.insert(f32::NAN);
+
+    let mut seen = HashSet::new();
+    assert!(seen.insert(a.clone()));
+    assert!(seen.insert(b.clone()));
+    assert!(!seen.insert(a.clone()));
+    assert!(seen.contains(&b));
+    assert_eq!(seen.len(), 2);
+}
+
+#[test]
+fn test_insert_unique() {
+    let mut set = WeakSet::new();
+    let a = set.insert_unique("a").unwrap();
+    let b = set.insert_unique("b").unwrap();
+
+    let dup = set.insert_unique("a").unwrap_err();
+    assert!(dup.ptr_eq(&a));
+    assert_eq!(set.len(), 2);
+
+    // once the value is gone, it can be inserted again
+    drop((a, dup));
+    assert!(set.insert_unique("a").is_ok());
+    drop(b);
+}
+
+#[test]
+fn test_on_drop() {
+    use std::cell::RefCell;
+
+    let freed = Rc::new(RefCell::new(vec![]));
+    let mut set = WeakSet::new();
+    {
+        let freed = freed.clone();
+        set.on_drop(move |index| freed.borrow_mut().push(index));
+    }
+
+    let a = set.insert(1);
+    let b = set.insert(2);
+    let c = b.clone();
+
+    // only the last entry frees the slot
+    drop(b);
+    assert!(freed.borrow().is_empty());
+    drop(c);
+    assert_eq!(*freed.borrow(), [1]);
+
+    set.clear();
+    drop(a);
+    assert_eq!(*freed.borrow(), [1]);
+}
+
+#[test]
+fn test_slot_count() {
+    let mut set = WeakSet::new();
+    let mut entries = set.extend_entries(0 .. 3);
+    assert_eq!((set.live_count(), set.slot_count()), (3, 3));
+
+    entries.remove(1);
+    assert_eq!((set.live_count(), set.slot_count()), (2, 3));
+}
+
+#[test]
+fn test_insert_unsized() {
+    let mut strings: WeakSet<Box<str>> = WeakSet::new();
+    let a = strings.insert_unsized("hello");
+    let b = strings.insert_unsized(String::from("world"));
+    assert_eq!(&*a.borrow_unboxed(), "hello");
+    assert_eq!(b.borrow_unboxed().len(), 5);
+    assert_eq!(&*a.borrow_str(), "hello");
+
+    let mut owned: WeakSet<String> = WeakSet::new();
+    let d = owned.insert(String::from("owned"));
+    assert_eq!(&*d.borrow_str(), "owned");
+
+    let mut bytes: WeakSet<Box<[u8]>> = WeakSet::new();
+    let c = bytes.insert_unsized(&[1u8, 2, 3][..]);
+    assert_eq!(&*c.borrow_unboxed(), [1, 2, 3]);
+}
+
+#[test]
+fn test_insert_all() {
+    let mut set = WeakSet::new();
+    let [a, b, c] = set.insert_all([1, 2, 3]);
+    assert_eq!(set.len(), 3);
+    assert_eq!((*a.borrow(), *b.borrow(), *c.borrow()), (1, 2, 3));
+
+    let none: [WeakSetEntry<i32>; 0] = set.insert_all([]);
+    assert!(none.is_empty());
+    assert_eq!(set.len(), 3);
+}
+
+#[test]
+fn test_position() {
+    let mut set = WeakSet::new();
+    let entries = set.extend_entries(["a", "b", "c"]);
+    assert_eq!(set.position(|&v| v == "b"), Some(1));
+    assert_eq!(set.position(|&v| v == "d"), None);
+
+    // no entry was created
+    assert_eq!(entries[1].refcount(), 1);
+}
+
+#[test]
+fn test_deep_clone() {
+    let mut set = WeakSet::new();
+    let [a, b] = set.insert_all([1, 2]);
+    drop(a);
+
+    let (copy, entries) = set.deep_clone();
+    assert!(copy == set);
+    *entries[0].borrow_mut() = 20;
+    assert_eq!(copy.to_vec(), [20]);
+    assert_eq!(set.to_vec(), [2]);
+
+    drop(entries);
+    assert!(copy.is_empty());
+    assert_eq!(*b.borrow(), 2);
+}
+
+#[test]
+fn test_reset() {
+    let mut set = WeakSet::new();
+    let [a, b, c] = set.insert_all([1, 2, 3]);
+    drop((c, a, b));
+    assert_eq!(set.first_free_index(), Some(1));
+
+    set.reset();
+    assert_eq!(set.first_free_index(), Some(0));
+    assert_eq!(set.slot_count(), 3);
+
+    let entries = set.extend_entries(4 .. 8);
+    let indices: Vec<usize> = entries.iter().map(|e| e.index()).collect();
+    assert_eq!(indices, [0, 1, 2, 3]);
+}
+
+#[test]
+#[should_panic(expected = "still holds values")]
+fn test_reset_live() {
+    let mut set = WeakSet::new();
+    let _a = set.insert(1);
+    set.reset();
+}
+
+#[test]
+fn test_borrow_two_mut() {
+    let mut set = WeakSet::new();
+    let [a, b] = set.insert_all([String::from("a"), String::from("b")]);
+
+    {
+        let (mut x, mut y) = set.borrow_two_mut(&a, &b).unwrap();
+        mem::swap(&mut *x, &mut *y);
+    }
+    assert_eq!(*a.borrow(), "b");
+    assert_eq!(*b.borrow(), "a");
+
+    assert!(set.borrow_two_mut(&a, &a.clone()).is_none());
+}
+
+#[test]
+fn test_entry_map() {
+    struct Node {
+        name: String,
+        weight: u32
+    }
+
+    let mut set = WeakSet::new();
+    let a = set.insert(Node { name: "a".into(), weight: 1 });
+
+    *a.map_mut(|node| &mut node.weight) += 1;
+    assert_eq!(&*a.map(|node| node.name.as_str()), "a");
+    assert_eq!(*a.map(|node| &node.weight), 2);
+}
+
+#[test]
+fn test_into_values() {
+    let mut set = WeakSet::new();
+    let mut entries = set.extend_entries(0 .. 3);
+    let other = set.clone();
+
+    // another handle could still use the set
+    let set = set.into_values().unwrap_err();
+    drop(other);
+
+    entries.remove(1);
+    assert_eq!(set.into_values().unwrap(), [0, 2]);
+    assert_eq!(entries[0].refcount(), 0);
+}
+
+#[test]
+fn test_collect_entries() {
+    let mut set = WeakSet::new();
+    let entries = set.extend_entries(0 .. 3);
+    let snapshot = set.collect_entries();
+    assert!(snapshot.iter().zip(&entries).all(|(a, b)| a.ptr_eq(b)));
+
+    drop(entries);
+    assert_eq!(set.len(), 3);
+    assert_eq!(*snapshot[2].borrow(), 2);
+}
+
+#[test]
+fn test_clear_with_entries() {
+    let mut set = WeakSet::new();
+    let entries = set.extend_entries(0 .. 3);
+    let weak = entries[0].weak();
+
+    set.clear();
+    assert!(set.is_empty());
+    assert!(weak.upgrade().is_none());
+    assert!(entries.iter().all(|e| e.refcount() == 0));
+
+    // neither does cloning them
+    let clone = entries[1].clone();
+    assert_eq!(clone.refcount(), 0);
+    drop(clone);
+
+    // dropping the orphaned entries does nothing
+    drop(entries);
+    assert!(set.is_empty());
+    assert_eq!(set.first_free_index(), Some(0));
+}
+
+#[test]
+fn test_get_disjoint_mut() {
+    let mut set = WeakSet::new();
+    let mut entries = set.extend_entries(0 .. 5);
+    entries.remove(3);
+
+    {
+        let [mut x, mut y, mut z] = set.get_disjoint_mut([4, 0, 2]).unwrap();
+        *x += 10;
+        *y += 20;
+        *z += 30;
+    }
+    assert_eq!(set.to_vec(), [20, 1, 32, 14]);
+
+    assert!(set.get_disjoint_mut([1, 2, 1]).is_none());
+    assert!(set.get_disjoint_mut([1, 3]).is_none());
+    assert!(set.get_disjoint_mut([1, 7]).is_none());
+    assert!(set.get_disjoint_mut::<0>([]).is_some());
+
+    // the borrow is released again
+    assert!(entries[0].try_borrow_mut().unwrap().is_some());
 }